@@ -11,12 +11,38 @@ pub fn benchmark_decode(c: &mut Criterion) {
 
     c.bench_function("decode_jpeg", |b| {
         b.iter(|| {
-            decode_binary(black_box(&data));
+            decode_binary(black_box(&data)).unwrap();
         })
     });
 }
 
-criterion_group!(benches, benchmark_decode);
+pub fn benchmark_decoder_reuse(c: &mut Criterion) {
+    let data = fs::read("img/maps.jpg").expect("Failed to read JPEG file");
+    let mut decoder = jpeg::Decoder::new();
+
+    c.bench_function("decode_jpeg_reused_decoder", |b| {
+        b.iter(|| {
+            decoder.decode(black_box(&data)).unwrap();
+        })
+    });
+}
+
+#[cfg(feature = "rayon")]
+pub fn benchmark_parallel_decode(c: &mut Criterion) {
+    let data = fs::read("img/maps.jpg").expect("Failed to read JPEG file");
+    let options = jpeg::DecodeOptions { parallel: true, ..Default::default() };
+
+    c.bench_function("decode_jpeg_parallel", |b| {
+        b.iter(|| {
+            jpeg::decode_binary_with(black_box(&data), &options).unwrap();
+        })
+    });
+}
+
+#[cfg(not(feature = "rayon"))]
+criterion_group!(benches, benchmark_decode, benchmark_decoder_reuse);
+#[cfg(feature = "rayon")]
+criterion_group!(benches, benchmark_decode, benchmark_decoder_reuse, benchmark_parallel_decode);
 criterion_main!(benches);
 
 
@@ -0,0 +1,29 @@
+#![no_main]
+
+use jpeg::{Concealment, DecodeOptions, DecoderLimits, IncrementalDecoder, PixelFormat, RowDecoder};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Small enough for each run to be quick, the limits themselves being
+    // checked before anything is allocated
+    let limits = DecoderLimits { max_pixels: 1 << 16, ..Default::default() };
+    if jpeg::check_limits(data, &limits).is_err() {
+        return;
+    }
+    let options = DecodeOptions { limits, ..Default::default() };
+
+    let _ = jpeg::decode_binary_with(data, &options);
+    let _ = jpeg::decode_with(data, PixelFormat::Rgb8, &options);
+    let _ = jpeg::decode_planes_with(data, &limits);
+    let _ = jpeg::decode_region_with(data, 5, 3, 20, 10, &options);
+    if let Ok(rows) = RowDecoder::with_options(data, &options) {
+        rows.for_each(drop);
+    }
+    let lenient = DecodeOptions { concealment: Concealment::PreviousRow, ..options };
+    let _ = jpeg::decode_lenient(data, &lenient);
+
+    let mut decoder = IncrementalDecoder::with_limits(limits);
+    for chunk in data.chunks(512) {
+        decoder.feed(chunk);
+    }
+});
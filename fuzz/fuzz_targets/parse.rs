@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    jpeg::fuzzing::parse(data);
+    let _ = jpeg::probe(data);
+    let _ = jpeg::check_limits(data, &jpeg::DecoderLimits::default());
+    jpeg::dump_json(data);
+    let _ = jpeg::icc_profile(data);
+});
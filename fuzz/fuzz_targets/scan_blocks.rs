@@ -0,0 +1,9 @@
+#![no_main]
+
+use jpeg::DecoderLimits;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let limits = DecoderLimits { max_pixels: 1 << 22, ..Default::default() };
+    jpeg::fuzzing::scan_blocks(data, &limits);
+});
@@ -0,0 +1,14 @@
+#!/bin/sh
+# Seeds the corpus of every fuzz target with the test images, named after
+# their SHA-1 as cargo-fuzz does. Run from anywhere, then for instance:
+#     cargo +nightly fuzz run decode
+set -e
+
+fuzz=$(cd "$(dirname "$0")" && pwd)
+for target in "$fuzz"/fuzz_targets/*.rs; do
+    corpus="$fuzz/corpus/$(basename "$target" .rs)"
+    mkdir -p "$corpus"
+    for image in "$fuzz"/../img/*.jpg; do
+        cp "$image" "$corpus/$(sha1sum "$image" | cut -d ' ' -f 1)"
+    done
+done
@@ -0,0 +1,174 @@
+use crate::error::Error;
+use crate::info::probe;
+use crate::limits::{self, check_limits, DecoderLimits};
+use crate::parsing;
+use crate::scan;
+use crate::transf::ZIGZAG;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoefficientOrder {
+    /// Order of the entropy-coded data.
+    Zigzag,
+    /// Row-major order of the 8x8 block.
+    Natural,
+}
+
+/// Quantized coefficients of one component, as a grid of blocks covering
+/// the padded MCU grid.
+#[derive(Debug)]
+pub struct ComponentCoefficients {
+    pub id: u8,
+    pub factors: (u8, u8),
+    pub quantization_table: [u16; 64],
+    pub blocks_wide: usize,
+    pub blocks_high: usize,
+    /// Blocks in raster order, `blocks_wide` per row.
+    pub blocks: Vec<[i16; 64]>,
+}
+
+impl ComponentCoefficients {
+    pub fn block(&self, x: usize, y: usize) -> &[i16; 64] {
+        &self.blocks[y * self.blocks_wide + x]
+    }
+}
+
+#[derive(Debug)]
+pub struct CoefficientImage {
+    pub width: u16,
+    pub height: u16,
+    pub order: CoefficientOrder,
+    pub components: Vec<ComponentCoefficients>,
+}
+
+fn reorder<T: Copy + Default>(values: &[T; 64], order: CoefficientOrder) -> [T; 64] {
+    match order {
+        CoefficientOrder::Zigzag => *values,
+        CoefficientOrder::Natural => {
+            let mut res = [T::default(); 64];
+            for (v, row) in ZIGZAG.iter().enumerate() {
+                for (u, &i) in row.iter().enumerate() {
+                    res[v * 8 + u] = values[i];
+                }
+            }
+            res
+        }
+    }
+}
+
+/// Entropy decodes the image without dequantizing nor inverse transforming
+/// the blocks.
+pub fn read_coefficients(data: &[u8], order: CoefficientOrder) -> Result<CoefficientImage, Error> {
+    read_coefficients_with(data, order, &DecoderLimits::default())
+}
+
+/// Same as `read_coefficients`, within `limits`.
+pub fn read_coefficients_with(data: &[u8], order: CoefficientOrder, limits: &DecoderLimits) -> Result<CoefficientImage, Error> {
+    let info = probe(data)?;
+    check_limits(data, limits)?;
+    if info.progressive {
+        return Err(Error::Format("Progressive JPEG not supported"));
+    }
+
+    let segments = parsing::parse(data);
+    limits::validate(&segments, limits)?;
+    let blocks = scan::scan_blocks(&segments)?;
+    let sof = segments.start_of_frame.as_ref().unwrap();
+
+    let layout = scan::McuLayout::new(sof);
+    let (h_mcus, v_mcus) = (layout.mcus_wide, layout.mcus_high);
+    let blocks_per_mcu = layout.blocks_per_mcu();
+    if blocks.len() < h_mcus * v_mcus * blocks_per_mcu {
+        return Err(Error::Truncated);
+    }
+
+    let mut components = Vec::new();
+    let mut first_block = 0;
+    for (component, &(h, v)) in sof.components.iter().zip(&layout.factors) {
+        let destination = component.quantization_destination();
+        let table = segments.quantization_tables.iter()
+            .find(|t| t.destination == destination)
+            .ok_or(Error::Format("Missing quantization table"))?;
+
+        let blocks_wide = h_mcus * h;
+        let blocks_high = v_mcus * v;
+        let mut grid = vec![[0; 64]; blocks_wide * blocks_high];
+        for (m, mcu) in blocks.chunks_exact(blocks_per_mcu).enumerate() {
+            let (mx, my) = (m % h_mcus, m / h_mcus);
+            for (b, block) in mcu[first_block..first_block + h * v].iter().enumerate() {
+                let (bx, by) = (mx * h + b % h, my * v + b / h);
+                grid[by * blocks_wide + bx] = reorder(block, order);
+            }
+        }
+        first_block += h * v;
+
+        components.push(ComponentCoefficients {
+            id: component.id.number(),
+            factors: (h as u8, v as u8),
+            quantization_table: reorder(&table.table.map(|q| q as u16), order),
+            blocks_wide,
+            blocks_high,
+            blocks: grid,
+        });
+    }
+
+    Ok(CoefficientImage {
+        width: sof.width,
+        height: sof.height,
+        order,
+        components,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn test_read_coefficients() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let segments = parsing::parse(&data);
+        let blocks = scan::scan_blocks(&segments).unwrap();
+
+        let img = read_coefficients(&data, CoefficientOrder::Zigzag).unwrap();
+        assert_eq!((img.width, img.height), (34, 16));
+        assert_eq!(img.components.len(), 3);
+
+        let lum = &img.components[0];
+        assert_eq!((lum.id, lum.factors), (1, (2, 2)));
+        assert_eq!((lum.blocks_wide, lum.blocks_high), (6, 2));
+        assert_eq!(lum.block(1, 1), &blocks[3]);
+        assert_eq!(lum.block(2, 0), &blocks[6]);
+        assert_eq!(lum.quantization_table[..64], segments.quantization_tables[0].table.map(|q| q as u16));
+
+        let cr = &img.components[2];
+        assert_eq!((cr.blocks_wide, cr.blocks_high), (3, 1));
+        assert_eq!(cr.block(2, 0), &blocks[17]);
+    }
+
+    #[test]
+    fn test_read_coefficients_natural_order() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let zigzag = read_coefficients(&data, CoefficientOrder::Zigzag).unwrap();
+        let natural = read_coefficients(&data, CoefficientOrder::Natural).unwrap();
+
+        let (z, n) = (zigzag.components[0].block(0, 0), natural.components[0].block(0, 0));
+        assert_eq!(n[0], z[0]);
+        assert_eq!(n[1], z[1]);
+        assert_eq!(n[8], z[2]);
+        assert_eq!(n[63], z[63]);
+        assert_eq!(natural.components[0].quantization_table[8], zigzag.components[0].quantization_table[2]);
+    }
+
+    #[test]
+    fn test_read_coefficients_truncated() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        assert_eq!(read_coefficients(&data[..800], CoefficientOrder::Zigzag).unwrap_err(), Error::Truncated);
+    }
+
+    #[test]
+    fn test_read_coefficients_progressive() {
+        let data = fs::read("img/carnaval.jpg").expect("Failed to read image");
+        assert!(read_coefficients(&data, CoefficientOrder::Zigzag).is_err());
+    }
+}
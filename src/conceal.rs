@@ -0,0 +1,150 @@
+use std::fmt;
+
+use crate::error::Error;
+use crate::parsing::Segments;
+use crate::scan::ScanReader;
+
+/// How `decode_lenient` fills in the MCUs that could not be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Concealment {
+    /// Flat mid-grey MCUs.
+    #[default]
+    Grey,
+    /// Copy of the MCU above, grey on the first row.
+    PreviousRow,
+}
+
+/// What was concealed while decoding leniently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// The scan data ends at MCU `mcu`: it and the following ones were
+    /// concealed.
+    Truncated { mcu: usize },
+    /// Decoding failed at MCU `mcu` (invalid code, unexpected marker or
+    /// missing restart marker). The MCUs up to `resumed`, where decoding
+    /// went on after the next restart marker, or up to the end of the image
+    /// without one, were concealed.
+    Corrupt { mcu: usize, resumed: Option<usize> },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::Truncated { mcu } => write!(f, "Scan data truncated at MCU {mcu}, remaining MCUs concealed"),
+            Warning::Corrupt { mcu, resumed: Some(resumed) } => {
+                write!(f, "Corrupt data at MCU {mcu}, MCUs {mcu} to {} concealed", resumed - 1)
+            }
+            Warning::Corrupt { mcu, resumed: None } => {
+                write!(f, "Corrupt data at MCU {mcu} and no restart marker after it, remaining MCUs concealed")
+            }
+        }
+    }
+}
+
+/// Image decoded by `decode_lenient`, along with what was concealed.
+#[derive(Debug, PartialEq)]
+pub struct LenientImage {
+    pub pixels: Vec<Vec<[u8; 3]>>,
+    pub warnings: Vec<Warning>,
+}
+
+/// Same as `scan::scan_blocks`, but always returns the blocks of every MCU
+/// of the image: decoding skips to the next restart marker after corrupt
+/// data and missing MCUs are filled in according to `concealment`.
+pub fn scan_concealed(segments: &Segments, concealment: Concealment) -> Result<(Vec<[i16; 64]>, Vec<Warning>), Error> {
+    let mut reader = ScanReader::new(segments)?;
+    let layout = reader.layout().clone();
+    let total = layout.mcus_wide * layout.mcus_high;
+    let mut mcus = vec![None; total];
+    let mut warnings = Vec::new();
+
+    while reader.position() < total {
+        let mcu = reader.position();
+        if let Some(blocks) = reader.next_mcu(&segments.huffman_tables) {
+            mcus[mcu] = Some(blocks);
+            continue;
+        }
+        if reader.at_end() {
+            warnings.push(Warning::Truncated { mcu });
+            break;
+        }
+        let resumed = reader.resync().filter(|&next| next < total);
+        warnings.push(Warning::Corrupt { mcu, resumed });
+        if resumed.is_none() {
+            break;
+        }
+    }
+
+    let per_mcu = layout.blocks_per_mcu();
+    let mut blocks: Vec<[i16; 64]> = Vec::with_capacity(total * per_mcu);
+    for (m, mcu) in mcus.into_iter().enumerate() {
+        match (mcu, concealment) {
+            (Some(mcu), _) => blocks.extend(mcu),
+            (None, Concealment::PreviousRow) if m >= layout.mcus_wide => {
+                let above = (m - layout.mcus_wide) * per_mcu;
+                blocks.extend_from_within(above..above + per_mcu);
+            }
+            // All coefficients at 0: mid-grey once level shifted
+            (None, _) => blocks.extend(std::iter::repeat_n([0; 64], per_mcu)),
+        }
+    }
+    Ok((blocks, warnings))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fixtures;
+    use crate::{decode_binary, decode_lenient, DecodeOptions};
+    use super::*;
+
+    /// 64x48 4:2:0 image: 4x3 MCUs, one restart interval per MCU row.
+    fn fixture() -> Vec<u8> {
+        fixtures::fixture(64, 48, &[(2, 2), (1, 1), (1, 1)], 90, 4)
+    }
+
+    fn markers(data: &[u8]) -> Vec<usize> {
+        (0..data.len() - 1)
+            .filter(|&i| data[i] == 0xFF && (0xD0..=0xD7).contains(&data[i + 1]))
+            .collect()
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = fixture();
+        let clean = decode_binary(&data).unwrap();
+        let rst = markers(&data);
+        let truncated = &data[..rst[1] + 10];
+
+        let LenientImage { pixels: img, warnings } = decode_lenient(truncated, &DecodeOptions::default()).unwrap();
+        assert_eq!((img[0].len(), img.len()), (64, 48));
+        assert!(matches!(warnings[..], [Warning::Truncated { mcu }] if (8..12).contains(&mcu)), "{warnings:?}");
+        assert_eq!(img[..31], clean[..31]);
+        assert_eq!(img[47][63], [128, 128, 128]);
+
+        assert_eq!(decode_lenient(&data[..100], &DecodeOptions::default()), Err(crate::Error::Truncated));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let data = fixture();
+        let clean = decode_binary(&data).unwrap();
+
+        // Most of the second interval is lost, decoding stumbles on RST1
+        let rst = markers(&data);
+        let mut cut = rst[0] + 2 + (rst[1] - rst[0]) / 4;
+        if data[cut - 1] == 0xFF {
+            cut -= 1;
+        }
+        let corrupt = [&data[..cut], &data[rst[1]..]].concat();
+
+        let LenientImage { pixels: img, warnings } = decode_lenient(&corrupt, &DecodeOptions::default()).unwrap();
+        assert!(matches!(warnings[..], [Warning::Corrupt { mcu, resumed: Some(8) }] if (4..8).contains(&mcu)), "{warnings:?}");
+        assert_eq!(img[..15], clean[..15]);
+        assert_eq!(img[33..], clean[33..]);
+        assert_eq!(img[24][60], [128, 128, 128]);
+
+        let options = DecodeOptions { concealment: Concealment::PreviousRow, ..Default::default() };
+        let img = decode_lenient(&corrupt, &options).unwrap().pixels;
+        assert_eq!(img[24][60], clean[8][60]);
+    }
+}
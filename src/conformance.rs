@@ -0,0 +1,168 @@
+//! Conformance suite: every fixture of `img/` and generated images covering
+//! the sampling factors, restart intervals and odd sizes the decoder
+//! supports are decoded and compared against the `image` crate's decoder.
+//!
+//! Only baseline images are compared. Progressive images, like
+//! `img/carnaval.jpg`, and the extended and progressive frame markers
+//! (SOF1, SOF2, SOF6, SOF10) are not supported: they are only checked to
+//! be rejected cleanly, no decoded output is compared for them.
+//!
+//! The suite is a test module of the library rather than an integration
+//! test under `tests/` because the generated images come from
+//! `encoder::encode`, the crate's test-only encoder, which is not part of
+//! the public API.
+
+use std::fs;
+
+use crate::fixtures::fixture;
+use crate::{
+    decode, decode_binary, decode_binary_scaled, decode_binary_with, decode_lenient, decode_region, probe, DecodeOptions, Decoder,
+    Error, PixelFormat, RowDecoder, Scale, Upsampling,
+};
+
+/// Differences between two images of the same size.
+#[derive(Debug)]
+struct Comparison {
+    psnr: f64,
+    max_diff: u8,
+}
+
+fn compare(img: &[Vec<[u8; 3]>], reference: &image::RgbImage) -> Comparison {
+    assert_eq!((img[0].len() as u32, img.len() as u32), reference.dimensions());
+    let mut squares = 0.;
+    let mut max_diff = 0;
+    for (pixel, expected) in img.iter().flatten().zip(reference.pixels()) {
+        for (value, expected) in pixel.iter().zip(expected.0) {
+            let diff = value.abs_diff(expected);
+            squares += (diff as f64).powi(2);
+            max_diff = max_diff.max(diff);
+        }
+    }
+    let mse = squares / (3 * reference.width() * reference.height()) as f64;
+    Comparison { psnr: 10. * (255f64.powi(2) / mse).log10(), max_diff }
+}
+
+/// Decodes `data` and checks it against the reference decoder. Both use
+/// different IDCTs and colour conversions, so only close output is
+/// expected.
+fn check(data: &[u8], upsampling: Upsampling, min_psnr: f64, max_diff: u8, context: &str) {
+    let img = decode_binary_with(data, &DecodeOptions { upsampling, ..Default::default() }).unwrap();
+    let reference = image::load_from_memory(data).unwrap().to_rgb8();
+    let comparison = compare(&img, &reference);
+    assert!(comparison.psnr >= min_psnr && comparison.max_diff <= max_diff, "{context}: {comparison:?}");
+}
+
+#[test]
+fn test_fixtures() {
+    let mut decoder = Decoder::new();
+    for entry in fs::read_dir("img").expect("Failed to list images") {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "jpg") {
+            continue;
+        }
+        let context = path.display().to_string();
+        let data = fs::read(&path).expect("Failed to read image");
+
+        if probe(&data).unwrap().progressive {
+            let unsupported = Error::Format("Progressive JPEG not supported");
+            assert_eq!(decode_lenient(&data, &DecodeOptions::default()), Err(unsupported), "{context}");
+            assert!(decoder.decode(&data).is_err(), "{context}");
+            assert!(decode(&data, PixelFormat::Rgb8).is_err(), "{context}");
+            continue;
+        }
+        check(&data, Upsampling::Fancy, 45., 8, &context);
+        assert_eq!(decoder.decode(&data).unwrap(), decode_binary(&data).unwrap(), "{context}");
+    }
+}
+
+#[test]
+fn test_generated() {
+    let modes = [
+        vec![(1, 1)],
+        vec![(2, 2)],
+        vec![(1, 1), (1, 1), (1, 1)],
+        vec![(2, 1), (1, 1), (1, 1)],
+        vec![(1, 2), (1, 1), (1, 1)],
+        vec![(2, 2), (1, 1), (1, 1)],
+        vec![(4, 1), (1, 1), (1, 1)],
+        vec![(1, 4), (1, 1), (1, 1)],
+        vec![(4, 2), (1, 1), (1, 1)],
+        vec![(2, 2), (2, 1), (1, 2)],
+        vec![(3, 1), (1, 1), (1, 1)],
+    ];
+    let mut decoder = Decoder::new();
+    for (width, height) in [(1, 1), (7, 13), (33, 17), (50, 37)] {
+        for factors in &modes {
+            for restart_interval in [0, 1, 5] {
+                let context = format!("{width}x{height} {factors:?} restart {restart_interval}");
+                let data = fixture(width, height, factors, 90, restart_interval);
+                // With a ratio other than two along one direction, the
+                // reference repeats the chroma samples along both
+                let upsampling = if factors[0] == (4, 2) { Upsampling::Nearest } else { Upsampling::Fancy };
+                check(&data, upsampling, 45., 4, &context);
+
+                let img = decode_binary(&data).unwrap();
+                assert_eq!(decoder.decode(&data).unwrap(), img, "{context}");
+                assert_eq!(RowDecoder::new(&data).unwrap().collect::<Result<Vec<_>, _>>().unwrap().concat(), img, "{context}");
+                let lenient = decode_lenient(&data, &DecodeOptions::default()).unwrap();
+                assert_eq!((lenient.pixels, lenient.warnings), (img.clone(), vec![]), "{context}");
+                let decoded = decode(&data, PixelFormat::Rgb8).unwrap();
+                assert_eq!(decoded.data, img.concat().concat(), "{context}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_progressive_rejected() {
+    // Same headers as baseline images, but with the frame marker of each
+    // progressive or extended process
+    let data = fixture(24, 16, &[(2, 2), (1, 1), (1, 1)], 90, 0);
+    let sof = (0..data.len() - 1).find(|&i| data[i] == 0xFF && data[i + 1] == 0xC0).unwrap();
+    for marker in [0xC1, 0xC2, 0xC6, 0xCA] {
+        let mut data = data.clone();
+        data[sof + 1] = marker;
+        assert_eq!(probe(&data).unwrap().progressive, marker != 0xC1);
+        assert!(decode(&data, PixelFormat::Rgb8).is_err(), "{marker:X}");
+        assert!(decode_lenient(&data, &DecodeOptions::default()).is_err(), "{marker:X}");
+        assert!(Decoder::new().decode(&data).is_err(), "{marker:X}");
+    }
+}
+
+#[test]
+fn test_odd_sizes() {
+    let modes = [
+        vec![(1, 1)],
+        vec![(1, 1), (1, 1), (1, 1)],
+        vec![(2, 1), (1, 1), (1, 1)],
+        vec![(1, 2), (1, 1), (1, 1)],
+        vec![(2, 2), (1, 1), (1, 1)],
+        vec![(4, 1), (1, 1), (1, 1)],
+    ];
+    for (width, height) in [(17, 9), (1, 1), (4095, 3)] {
+        for factors in &modes {
+            let context = format!("{width}x{height} {factors:?}");
+            let data = fixture(width, height, factors, 90, 3);
+
+            let img = decode_binary(&data).unwrap();
+            assert_eq!((img[0].len(), img.len()), (width, height), "{context}");
+            let reference = image::load_from_memory(&data).unwrap().to_rgb8();
+            for (y, row) in img.iter().enumerate() {
+                for (x, pixel) in row.iter().enumerate() {
+                    let expected = reference.get_pixel(x as u32, y as u32).0;
+                    for c in 0..3 {
+                        assert!(pixel[c].abs_diff(expected[c]) <= 4, "{context}: {x}, {y}");
+                    }
+                }
+            }
+
+            let rows = RowDecoder::new(&data).unwrap().collect::<Result<Vec<_>, _>>().unwrap().concat();
+            assert_eq!(rows, img, "{context}");
+            let (x, y) = (width as u16 / 3, height as u16 / 2);
+            let region = decode_region(&data, x, y, 9, 5).unwrap();
+            assert_eq!(region[0][..], img[y as usize][x as usize..][..region[0].len()], "{context}");
+            let eighth = decode_binary_scaled(&data, Scale::Eighth).unwrap();
+            assert_eq!((eighth[0].len(), eighth.len()), (width.div_ceil(8), height.div_ceil(8)), "{context}");
+        }
+    }
+}
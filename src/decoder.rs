@@ -0,0 +1,199 @@
+use crate::error::Error;
+use crate::limits::{self, check_limits};
+use crate::options::DecodeOptions;
+use crate::parallel;
+use crate::parsing::{self, HuffmanTable, Segments};
+use crate::scan::McuLayout;
+use crate::transf::{self, McuSamples};
+
+/// Decoder reused from one image to the next, such as the frames of an
+/// MJPEG stream, to save allocations and table building: the coefficient
+/// and sample buffers are kept, and so are the Huffman tables, which are
+/// only built again when their DHT data changes.
+///
+/// An image without some Huffman table fails to decode, unless the decoder
+/// is made by `abbreviated` for an abbreviated stream.
+///
+/// The decoder is `Send` and `Sync`, so it can be moved to a worker thread
+/// or shared behind a mutex.
+#[derive(Default)]
+pub struct Decoder {
+    options: DecodeOptions,
+    /// Whether an image may use the Huffman tables of the previous one.
+    abbreviated: bool,
+    /// Huffman tables of the last image, along with their bytes in the DHT
+    /// segment.
+    huffman: Vec<(Vec<u8>, HuffmanTable)>,
+    blocks: Vec<[i16; 64]>,
+    samples: Vec<McuSamples>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// `options.concealment` is not used: the decoding is strict.
+    pub fn with_options(options: DecodeOptions) -> Decoder {
+        Decoder { options, ..Default::default() }
+    }
+
+    /// Decoder for an abbreviated stream, such as some MJPEG ones: an image
+    /// without some Huffman table uses the one the previous image defined
+    /// for the same class and destination.
+    pub fn abbreviated(options: DecodeOptions) -> Decoder {
+        Decoder { options, abbreviated: true, ..Default::default() }
+    }
+
+    pub fn options(&self) -> &DecodeOptions {
+        &self.options
+    }
+
+    /// Same as `decode_binary_with`, failing instead of panicking on
+    /// images the decoder cannot handle and on truncated scan data.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<Vec<[u8; 3]>>, Error> {
+        let options = self.options;
+        check_limits(data, &options.limits)?;
+        let segments = self.parse(data);
+        limits::validate(&segments, &options.limits)?;
+
+        let layout = McuLayout::new(segments.start_of_frame.as_ref().unwrap());
+        let per_mcu = layout.blocks_per_mcu();
+        let total = layout.mcus_wide * layout.mcus_high;
+        parallel::scan_blocks_into(&segments, options.parallel, &mut self.blocks)?;
+        if self.blocks.len() < total * per_mcu {
+            return Err(Error::Truncated);
+        }
+
+        let tables = transf::block_tables(&segments);
+        let blocks = &self.blocks;
+        self.samples.resize_with(total, Vec::new);
+        parallel::for_each(&mut self.samples, options.parallel, |m, samples| {
+            transf::blocks_to_samples_into(&blocks[m * per_mcu..][..per_mcu], &tables, options.scale, samples)
+        });
+        Ok(transf::samples_to_image(
+            &layout,
+            &self.samples,
+            options.scale,
+            options.upsampling,
+            options.conversion,
+            options.parallel,
+        ))
+    }
+
+    /// Parses `data`, reusing the Huffman tables of the last image that are
+    /// defined again, and, if `abbreviated`, the ones that are not.
+    fn parse<'a>(&mut self, data: &'a [u8]) -> Segments<'a> {
+        let cache = std::mem::take(&mut self.huffman);
+        let mut used = Vec::with_capacity(cache.len());
+        let mut segments = parsing::parse_with(data, |segment| {
+            let table = match cache.iter().find(|(cached, _)| cached == segment) {
+                Some((_, table)) => table.clone(),
+                None => HuffmanTable::new(segment)?,
+            };
+            used.push((segment.to_vec(), table.clone()));
+            Some(table)
+        });
+
+        for (segment, table) in cache.into_iter().filter(|_| self.abbreviated) {
+            let defined = |t: &HuffmanTable| t.class == table.class && t.destination == table.destination;
+            if !segments.huffman_tables.iter().any(defined) {
+                segments.huffman_tables.push(table.clone());
+                used.push((segment, table));
+            }
+        }
+        self.huffman = used;
+        segments
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use crate::encoder::encode;
+    use crate::fixtures::pattern;
+    use crate::{decode_binary, decode_binary_with, Scale};
+    use super::*;
+
+    fn frame(shift: usize, quality: u8) -> Vec<u8> {
+        frame_with_restarts(shift, quality, 0)
+    }
+
+    /// Frames of a moving picture, `shift` pixels apart.
+    fn frame_with_restarts(shift: usize, quality: u8, restart_interval: u16) -> Vec<u8> {
+        let pixels: Vec<Vec<[u8; 3]>> = pattern(64, 40).into_iter().map(|row| row[shift..shift + 56].to_vec()).collect();
+        encode(&pixels, &[(2, 1), (1, 1), (1, 1)], quality, restart_interval)
+    }
+
+    /// `data` without its DHT segments.
+    fn abbreviated(data: &[u8]) -> Vec<u8> {
+        let mut res = Vec::new();
+        let mut i = 0;
+        while i + 1 < data.len() && !(data[i] == 0xFF && data[i + 1] == 0xDA) {
+            let length = 2 + u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+            let marker = if data[i + 1] == 0xD8 { 2 } else { length };
+            if data[i + 1] != 0xC4 {
+                res.extend_from_slice(&data[i..i + marker]);
+            }
+            i += marker;
+        }
+        res.extend_from_slice(&data[i..]);
+        res
+    }
+
+    #[test]
+    fn test_decoder() {
+        let mut decoder = Decoder::new();
+        let images = [
+            fs::read("img/rec32dot.jpg").expect("Failed to read image"),
+            frame(0, 75),
+            frame(3, 75),
+            fs::read("img/maps.jpg").expect("Failed to read image"),
+            frame(5, 40),
+        ];
+        for data in &images {
+            assert_eq!(decoder.decode(data).unwrap(), decode_binary(data).unwrap());
+        }
+
+        // An image without Huffman tables does not use the previous ones
+        let first = frame(0, 75);
+        let next = frame(7, 75);
+        assert_eq!(decoder.decode(&first).unwrap(), decode_binary(&first).unwrap());
+        assert!(decoder.decode(&abbreviated(&next)).is_err());
+
+        // Still usable after a failure
+        let scan_start = parsing::headers_length(&next).unwrap();
+        assert_eq!(decoder.decode(&next[..(scan_start + next.len()) / 2]), Err(Error::Truncated));
+        assert_eq!(decoder.decode(&next).unwrap(), decode_binary(&next).unwrap());
+
+        let options = DecodeOptions { scale: Scale::Half, ..Default::default() };
+        let mut decoder = Decoder::with_options(options);
+        for data in &images {
+            assert_eq!(decoder.decode(data).unwrap(), decode_binary_with(data, &options).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_decoder_abbreviated() {
+        // MJPEG frames relying on the tables of the first one
+        let mut decoder = Decoder::abbreviated(DecodeOptions::default());
+        let first = frame(0, 75);
+        let next = frame(7, 75);
+        assert!(decoder.decode(&abbreviated(&first)).is_err());
+        assert_eq!(decoder.decode(&first).unwrap(), decode_binary(&first).unwrap());
+        assert_eq!(decoder.decode(&abbreviated(&next)).unwrap(), decode_binary(&next).unwrap());
+        assert_eq!(decoder.decode(&abbreviated(&first)).unwrap(), decode_binary(&first).unwrap());
+    }
+
+    #[test]
+    fn test_decoder_parallel() {
+        // Restart intervals are decoded in parallel with the rayon feature,
+        // using the tables the previous frame defined
+        let mut decoder = Decoder::abbreviated(DecodeOptions { parallel: true, ..Default::default() });
+        let first = frame_with_restarts(0, 75, 3);
+        let next = frame_with_restarts(7, 75, 3);
+        assert_eq!(decoder.decode(&first).unwrap(), decode_binary(&first).unwrap());
+        assert_eq!(decoder.decode(&abbreviated(&next)).unwrap(), decode_binary(&next).unwrap());
+        assert!(Decoder::with_options(*decoder.options()).decode(&abbreviated(&next)).is_err());
+    }
+}
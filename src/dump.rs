@@ -0,0 +1,473 @@
+use std::fmt::Write;
+
+use crate::error::Error;
+use crate::parsing::get_lenght;
+use crate::transf::ZIGZAG;
+
+#[derive(Debug, PartialEq)]
+pub struct QuantizationFields {
+    pub id: u8,
+    /// 8 or 16 bits.
+    pub precision: u8,
+    /// Values in natural (row-major) order.
+    pub table: [[u16; 8]; 8],
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HuffmanFields {
+    /// 0 for DC, 1 for AC.
+    pub class: u8,
+    pub id: u8,
+    /// Number of codes of each length, from 1 to 16 bits.
+    pub code_lengths: [u8; 16],
+    pub symbols: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FrameComponent {
+    pub id: u8,
+    pub factors: (u8, u8),
+    pub quantization_table: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScanComponent {
+    pub id: u8,
+    pub dc_table: u8,
+    pub ac_table: u8,
+}
+
+/// Decoded content of a segment, for the markers this crate knows about.
+#[derive(Debug, PartialEq)]
+pub enum Fields {
+    None,
+    Application { identifier: String },
+    Quantization(Vec<QuantizationFields>),
+    Huffman(Vec<HuffmanFields>),
+    Frame {
+        precision: u8,
+        height: u16,
+        width: u16,
+        components: Vec<FrameComponent>,
+    },
+    Scan {
+        components: Vec<ScanComponent>,
+        /// Spectral selection start and end.
+        ss: u8,
+        se: u8,
+        /// Successive approximation high and low bits.
+        ah: u8,
+        al: u8,
+        /// Size of the entropy-coded data following the header.
+        entropy_length: usize,
+        restart_markers: usize,
+    },
+    RestartInterval(u16),
+    Comment(String),
+}
+
+/// A marker segment of the file. `length` counts the bytes following the
+/// marker, including the length field itself; it is 0 for markers without
+/// payload (SOI, EOI, RSTn).
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    pub marker: u8,
+    pub offset: usize,
+    pub length: usize,
+    pub fields: Fields,
+}
+
+impl Segment {
+    pub fn name(&self) -> &'static str {
+        marker_name(self.marker)
+    }
+}
+
+pub fn marker_name(marker: u8) -> &'static str {
+    const APP: [&str; 16] = [
+        "APP0", "APP1", "APP2", "APP3", "APP4", "APP5", "APP6", "APP7",
+        "APP8", "APP9", "APP10", "APP11", "APP12", "APP13", "APP14", "APP15",
+    ];
+    const RST: [&str; 8] = ["RST0", "RST1", "RST2", "RST3", "RST4", "RST5", "RST6", "RST7"];
+    const SOF: [&str; 16] = [
+        "SOF0", "SOF1", "SOF2", "SOF3", "DHT", "SOF5", "SOF6", "SOF7",
+        "JPG", "SOF9", "SOF10", "SOF11", "DAC", "SOF13", "SOF14", "SOF15",
+    ];
+
+    match marker {
+        0xC0..=0xCF => SOF[(marker - 0xC0) as usize],
+        0xD0..=0xD7 => RST[(marker - 0xD0) as usize],
+        0xD8 => "SOI",
+        0xD9 => "EOI",
+        0xDA => "SOS",
+        0xDB => "DQT",
+        0xDC => "DNL",
+        0xDD => "DRI",
+        0xE0..=0xEF => APP[(marker - 0xE0) as usize],
+        0xFE => "COM",
+        _ => "unknown",
+    }
+}
+
+/// Offset of the first marker following the entropy-coded data at `start`,
+/// along with the number of restart markers within the data.
+pub fn skip_entropy_data(data: &[u8], start: usize) -> (usize, usize) {
+    let mut restarts = 0;
+    let mut i = start;
+    while i + 1 < data.len() {
+        if data[i] == 0xFF && (0xD0..=0xD7).contains(&data[i + 1]) {
+            restarts += 1;
+        } else if data[i] == 0xFF && data[i + 1] != 0x00 {
+            return (i, restarts);
+        }
+        i += 1;
+    }
+    (data.len(), restarts)
+}
+
+fn quantization_fields(mut bytes: &[u8]) -> Result<Fields, Error> {
+    let mut tables = Vec::new();
+    while let Some(&pq_tq) = bytes.first() {
+        let precision = if pq_tq >> 4 == 0 { 8 } else { 16 };
+        let size = 64 * precision as usize / 8;
+        let values = bytes.get(1..1 + size).ok_or(Error::Format("Wrong quantization table length"))?;
+
+        let mut table = [[0; 8]; 8];
+        for (v, row) in ZIGZAG.iter().enumerate() {
+            for (u, &i) in row.iter().enumerate() {
+                table[v][u] = if precision == 8 {
+                    values[i] as u16
+                } else {
+                    u16::from_be_bytes([values[2 * i], values[2 * i + 1]])
+                };
+            }
+        }
+        tables.push(QuantizationFields { id: pq_tq & 0x0F, precision, table });
+        bytes = &bytes[1 + size..];
+    }
+    Ok(Fields::Quantization(tables))
+}
+
+fn huffman_fields(mut bytes: &[u8]) -> Result<Fields, Error> {
+    let mut tables = Vec::new();
+    while let Some(&tc_th) = bytes.first() {
+        let code_lengths: [u8; 16] = bytes.get(1..17)
+            .ok_or(Error::Format("Wrong Huffman table length"))?
+            .try_into()
+            .unwrap();
+        let n_symbols: usize = code_lengths.iter().map(|&n| n as usize).sum();
+        let symbols = bytes.get(17..17 + n_symbols).ok_or(Error::Format("Wrong Huffman table length"))?;
+
+        tables.push(HuffmanFields {
+            class: tc_th >> 4,
+            id: tc_th & 0x0F,
+            code_lengths,
+            symbols: symbols.to_vec(),
+        });
+        bytes = &bytes[17 + n_symbols..];
+    }
+    Ok(Fields::Huffman(tables))
+}
+
+fn frame_fields(bytes: &[u8]) -> Result<Fields, Error> {
+    if bytes.len() < 6 || bytes.len() < 6 + 3 * bytes[5] as usize {
+        return Err(Error::Format("Wrong frame header length"));
+    }
+    Ok(Fields::Frame {
+        precision: bytes[0],
+        height: u16::from_be_bytes([bytes[1], bytes[2]]),
+        width: u16::from_be_bytes([bytes[3], bytes[4]]),
+        components: bytes[6..6 + 3 * bytes[5] as usize]
+            .chunks_exact(3)
+            .map(|c| FrameComponent {
+                id: c[0],
+                factors: (c[1] >> 4, c[1] & 0x0F),
+                quantization_table: c[2],
+            })
+            .collect(),
+    })
+}
+
+fn scan_fields(bytes: &[u8], entropy_length: usize, restart_markers: usize) -> Result<Fields, Error> {
+    let n = *bytes.first().ok_or(Error::Format("Wrong scan header length"))? as usize;
+    if bytes.len() < 4 + 2 * n {
+        return Err(Error::Format("Wrong scan header length"));
+    }
+    Ok(Fields::Scan {
+        components: bytes[1..1 + 2 * n]
+            .chunks_exact(2)
+            .map(|c| ScanComponent { id: c[0], dc_table: c[1] >> 4, ac_table: c[1] & 0x0F })
+            .collect(),
+        ss: bytes[1 + 2 * n],
+        se: bytes[2 + 2 * n],
+        ah: bytes[3 + 2 * n] >> 4,
+        al: bytes[3 + 2 * n] & 0x0F,
+        entropy_length,
+        restart_markers,
+    })
+}
+
+/// Lists the marker segments of the file, in order, with their decoded
+/// fields. Listing stops at the first malformed or truncated segment: the
+/// segments before it are returned along with the error.
+pub fn segments(data: &[u8]) -> (Vec<Segment>, Option<Error>) {
+    let mut res = Vec::new();
+    let error = read_segments(data, &mut res).err();
+    (res, error)
+}
+
+fn read_segments(data: &[u8], res: &mut Vec<Segment>) -> Result<(), Error> {
+    let mut i = 0;
+    while i < data.len() {
+        let marker = match data.get(i..i + 2) {
+            Some([0xFF, 0xFF]) => {
+                i += 1;
+                continue;
+            }
+            Some(&[0xFF, marker]) => marker,
+            Some(_) => return Err(Error::Format("Expected a marker")),
+            None => return Err(Error::Truncated),
+        };
+
+        if matches!(marker, 0xD8 | 0xD9 | 0x01 | 0xD0..=0xD7) {
+            res.push(Segment { marker, offset: i, length: 0, fields: Fields::None });
+            i += 2;
+            if marker == 0xD9 {
+                break;
+            }
+            continue;
+        }
+
+        let length = get_lenght(data.get(i + 2..i + 4).ok_or(Error::Truncated)?);
+        if length < 2 {
+            return Err(Error::Format("Wrong segment length"));
+        }
+        let bytes = data.get(i + 4..i + 2 + length).ok_or(Error::Truncated)?;
+        let offset = i;
+        i += 2 + length;
+
+        let fields = match marker {
+            0xE0..=0xEF => Fields::Application {
+                identifier: bytes.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect(),
+            },
+            0xDB => quantization_fields(bytes)?,
+            0xC4 => huffman_fields(bytes)?,
+            0xC0..=0xCF if marker != 0xC8 && marker != 0xCC => frame_fields(bytes)?,
+            0xDA => {
+                let (end, restarts) = skip_entropy_data(data, i);
+                let fields = scan_fields(bytes, end - i, restarts)?;
+                i = end;
+                fields
+            }
+            0xDD if bytes.len() >= 2 => Fields::RestartInterval(u16::from_be_bytes([bytes[0], bytes[1]])),
+            0xFE => Fields::Comment(String::from_utf8_lossy(bytes).into_owned()),
+            _ => Fields::None,
+        };
+        res.push(Segment { marker, offset, length, fields });
+    }
+    Ok(())
+}
+
+fn join<T: ToString>(values: &[T], sep: &str) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(sep)
+}
+
+/// Human-readable listing of the segments, ending with the error that
+/// stopped it if any.
+pub fn dump_text(data: &[u8]) -> String {
+    let mut out = String::new();
+
+    let (segments, error) = segments(data);
+    for segment in segments {
+        if segment.length > 0 {
+            write!(out, "{:>8}  {:<5} length {}", segment.offset, segment.name(), segment.length).unwrap();
+        } else {
+            write!(out, "{:>8}  {}", segment.offset, segment.name()).unwrap();
+        }
+        match &segment.fields {
+            Fields::None => writeln!(out).unwrap(),
+            Fields::Application { identifier } => writeln!(out, ": {identifier:?}").unwrap(),
+            Fields::Quantization(tables) => {
+                writeln!(out).unwrap();
+                for t in tables {
+                    writeln!(out, "          table {}, {} bits", t.id, t.precision).unwrap();
+                    for row in &t.table {
+                        writeln!(out, "            {}", row.map(|v| format!("{v:>4}")).join("")).unwrap();
+                    }
+                }
+            }
+            Fields::Huffman(tables) => {
+                writeln!(out).unwrap();
+                for t in tables {
+                    let class = if t.class == 0 { "DC" } else { "AC" };
+                    writeln!(out, "          {class} table {}, {} codes", t.id, t.symbols.len()).unwrap();
+                    writeln!(out, "            lengths: {}", join(&t.code_lengths, " ")).unwrap();
+                    let symbols: Vec<String> = t.symbols.iter().map(|s| format!("{s:02x}")).collect();
+                    writeln!(out, "            symbols: {}", symbols.join(" ")).unwrap();
+                }
+            }
+            Fields::Frame { precision, height, width, components } => {
+                writeln!(out, ": {width}x{height}, {precision} bits, {} components", components.len()).unwrap();
+                for c in components {
+                    writeln!(
+                        out,
+                        "          component {}: sampling {}x{}, quantization table {}",
+                        c.id, c.factors.0, c.factors.1, c.quantization_table
+                    ).unwrap();
+                }
+            }
+            Fields::Scan { components, ss, se, ah, al, entropy_length, restart_markers } => {
+                writeln!(out, ": Ss {ss}, Se {se}, Ah {ah}, Al {al}").unwrap();
+                for c in components {
+                    writeln!(out, "          component {}: DC table {}, AC table {}", c.id, c.dc_table, c.ac_table).unwrap();
+                }
+                writeln!(out, "          entropy-coded data: {entropy_length} bytes, {restart_markers} restart markers").unwrap();
+            }
+            Fields::RestartInterval(interval) => writeln!(out, ": {interval} MCUs").unwrap(),
+            Fields::Comment(comment) => writeln!(out, ": {comment:?}").unwrap(),
+        }
+    }
+    if let Some(error) = error {
+        writeln!(out, "error: {error}").unwrap();
+    }
+    out
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(res, "\\u{:04x}", c as u32).unwrap(),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn json_fields(fields: &Fields) -> String {
+    match fields {
+        Fields::None => String::new(),
+        Fields::Application { identifier } => format!(", \"identifier\": {}", json_string(identifier)),
+        Fields::Quantization(tables) => {
+            let tables: Vec<String> = tables.iter().map(|t| {
+                let rows: Vec<String> = t.table.iter().map(|row| format!("[{}]", join(row, ", "))).collect();
+                format!("{{\"id\": {}, \"precision\": {}, \"table\": [{}]}}", t.id, t.precision, rows.join(", "))
+            }).collect();
+            format!(", \"tables\": [{}]", tables.join(", "))
+        }
+        Fields::Huffman(tables) => {
+            let tables: Vec<String> = tables.iter().map(|t| format!(
+                "{{\"class\": \"{}\", \"id\": {}, \"code_lengths\": [{}], \"symbols\": [{}]}}",
+                if t.class == 0 { "DC" } else { "AC" }, t.id, join(&t.code_lengths, ", "), join(&t.symbols, ", ")
+            )).collect();
+            format!(", \"tables\": [{}]", tables.join(", "))
+        }
+        Fields::Frame { precision, height, width, components } => {
+            let components: Vec<String> = components.iter().map(|c| format!(
+                "{{\"id\": {}, \"h\": {}, \"v\": {}, \"quantization_table\": {}}}",
+                c.id, c.factors.0, c.factors.1, c.quantization_table
+            )).collect();
+            format!(
+                ", \"precision\": {precision}, \"width\": {width}, \"height\": {height}, \"components\": [{}]",
+                components.join(", ")
+            )
+        }
+        Fields::Scan { components, ss, se, ah, al, entropy_length, restart_markers } => {
+            let components: Vec<String> = components.iter().map(|c| format!(
+                "{{\"id\": {}, \"dc_table\": {}, \"ac_table\": {}}}", c.id, c.dc_table, c.ac_table
+            )).collect();
+            format!(
+                ", \"components\": [{}], \"ss\": {ss}, \"se\": {se}, \"ah\": {ah}, \"al\": {al}, \
+                \"entropy_length\": {entropy_length}, \"restart_markers\": {restart_markers}",
+                components.join(", ")
+            )
+        }
+        Fields::RestartInterval(interval) => format!(", \"interval\": {interval}"),
+        Fields::Comment(comment) => format!(", \"comment\": {}", json_string(comment)),
+    }
+}
+
+/// Same listing as `dump_text`, as a JSON array with one object per segment.
+/// The error that stopped the listing, if any, is a last `{"error": ...}`
+/// object.
+pub fn dump_json(data: &[u8]) -> String {
+    let (segments, error) = segments(data);
+    let mut objects: Vec<String> = segments.iter().map(|s| format!(
+        "  {{\"marker\": \"{}\", \"code\": {}, \"offset\": {}, \"length\": {}{}}}",
+        s.name(), s.marker, s.offset, s.length, json_fields(&s.fields)
+    )).collect();
+    if let Some(error) = error {
+        objects.push(format!("  {{\"error\": {}}}", json_string(&error.to_string())));
+    }
+    format!("[\n{}\n]\n", objects.join(",\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn test_segments() {
+        let data = fs::read("img/white_square.jpg").expect("Failed to read image");
+        let (segments, error) = segments(&data);
+        assert_eq!(error, None);
+
+        let names: Vec<&str> = segments.iter().map(|s| s.name()).collect();
+        assert_eq!(names, ["SOI", "APP0", "DQT", "DQT", "SOF0", "DHT", "DHT", "DHT", "DHT", "SOS", "EOI"]);
+        assert_eq!((segments[1].marker, segments[1].offset, segments[1].length), (0xE0, 2, 16));
+        assert_eq!(segments[10].offset, data.len() - 2);
+
+        // The segments before a truncated one are kept
+        let (partial, error) = super::segments(&data[..30]);
+        assert_eq!(partial.iter().map(|s| s.name()).collect::<Vec<_>>(), ["SOI", "APP0"]);
+        assert_eq!(error, Some(Error::Truncated));
+    }
+
+    #[test]
+    fn test_segment_fields() {
+        let data = fs::read("img/white_square.jpg").expect("Failed to read image");
+        let (segments, _) = segments(&data);
+
+        let Fields::Quantization(tables) = &segments[2].fields else { panic!("DQT fields") };
+        assert_eq!(tables[0].table[0][..3], [2, 1, 1]);
+        assert_eq!(tables[0].table[1][0], 1);
+
+        let Fields::Huffman(tables) = &segments[5].fields else { panic!("DHT fields") };
+        assert_eq!((tables[0].class, tables[0].id, tables[0].symbols.len()), (0, 0, 12));
+
+        let Fields::Frame { width, components, .. } = &segments[4].fields else { panic!("SOF fields") };
+        assert_eq!(*width, 8);
+        assert_eq!(components[0], FrameComponent { id: 1, factors: (2, 2), quantization_table: 0 });
+
+        let Fields::Scan { components, ss, se, entropy_length, restart_markers, .. } = &segments[9].fields else {
+            panic!("SOS fields")
+        };
+        assert_eq!(components.len(), 3);
+        assert_eq!((*ss, *se, *entropy_length, *restart_markers), (0, 63, 6, 0));
+    }
+
+    #[test]
+    fn test_dump_text_and_json() {
+        let data = fs::read("img/white_square.jpg").expect("Failed to read image");
+
+        let text = dump_text(&data);
+        assert!(text.contains("SOF0  length 17: 8x8, 8 bits, 3 components"));
+        assert!(text.contains("entropy-coded data: 6 bytes, 0 restart markers"));
+
+        let json = dump_json(&data);
+        assert!(json.starts_with("[\n  {\"marker\": \"SOI\", \"code\": 216, \"offset\": 0, \"length\": 0},"));
+        assert!(json.contains("\"identifier\": \"JFIF\""));
+        assert_eq!(json.matches("\"marker\"").count(), 11);
+
+        let text = dump_text(&data[..30]);
+        assert!(text.contains("APP0  length 16"));
+        assert!(text.ends_with("error: Truncated JPEG data\n"));
+        let json = dump_json(&data[..30]);
+        assert_eq!(json.matches("\"marker\"").count(), 2);
+        assert!(json.ends_with("  {\"error\": \"Truncated JPEG data\"}\n]\n"));
+    }
+}
@@ -0,0 +1,371 @@
+//! Minimal baseline encoder, writing quantized coefficients with the
+//! Huffman tables of Annex K for the lossless transforms. Tests also use it
+//! to generate images of any size, sampling factors and restart interval.
+
+use crate::coefficients::{CoefficientImage, CoefficientOrder};
+use crate::error::Error;
+#[cfg(test)]
+use crate::coefficients::ComponentCoefficients;
+#[cfg(test)]
+use crate::quality::{scaled_table, CHROMINANCE_QUANTIZATION, LUMINANCE_QUANTIZATION};
+#[cfg(test)]
+use crate::transf::ZIGZAG;
+
+const DC_LUMINANCE_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_CHROMINANCE_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const AC_LUMINANCE_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+const AC_LUMINANCE_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+const AC_CHROMINANCE_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const AC_CHROMINANCE_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+    0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+    0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+    0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+    0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+    0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+/// Code and length of each symbol.
+struct HuffmanCodes([(u16, u8); 256]);
+
+impl HuffmanCodes {
+    fn new(bits: &[u8; 16], values: &[u8]) -> HuffmanCodes {
+        let mut codes = [(0, 0); 256];
+        let mut code = 0;
+        let mut values = values.iter();
+        for (n, &count) in bits.iter().enumerate() {
+            for _ in 0..count {
+                codes[*values.next().unwrap() as usize] = (code, n as u8 + 1);
+                code += 1;
+            }
+            code <<= 1;
+        }
+        HuffmanCodes(codes)
+    }
+}
+
+struct BitWriter {
+    data: Vec<u8>,
+    acc: u32,
+    n_bits: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u16, length: u8) {
+        for i in (0..length).rev() {
+            self.acc = (self.acc << 1) | ((value >> i) & 1) as u32;
+            self.n_bits += 1;
+            if self.n_bits == 8 {
+                self.data.push(self.acc as u8);
+                if self.acc as u8 == 0xFF {
+                    self.data.push(0x00);
+                }
+                self.acc = 0;
+                self.n_bits = 0;
+            }
+        }
+    }
+
+    /// Pads the last byte with ones.
+    fn flush(&mut self) {
+        while self.n_bits != 0 {
+            self.write(1, 1);
+        }
+    }
+}
+
+/// Number of bits of `value`, and its bits as stored after the category.
+fn category(value: i32) -> (u8, u16) {
+    let size = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 { value - 1 } else { value };
+    (size, (bits & ((1 << size) - 1)) as u16)
+}
+
+/// Forward DCT and quantization of a level shifted block, in zigzag order.
+#[cfg(test)]
+fn fdct(block: &[[f32; 8]; 8], table: &[u8; 64]) -> [i16; 64] {
+    let c = |k: usize| if k == 0 { std::f32::consts::FRAC_1_SQRT_2 } else { 1. };
+    let mut res = [0; 64];
+    for (v, row) in ZIGZAG.iter().enumerate() {
+        for (u, &i) in row.iter().enumerate() {
+            let mut sum = 0.;
+            for (y, samples) in block.iter().enumerate() {
+                for (x, &sample) in samples.iter().enumerate() {
+                    sum += sample
+                        * f32::cos((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / 16.)
+                        * f32::cos((2 * y + 1) as f32 * v as f32 * std::f32::consts::PI / 16.);
+                }
+            }
+            let coeff = c(u) * c(v) * sum / 4.;
+            res[i] = (coeff / table[i] as f32).round() as i16;
+        }
+    }
+    res
+}
+
+fn segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    out.extend([0xFF, marker]);
+    out.extend(((payload.len() + 2) as u16).to_be_bytes());
+    out.extend(payload);
+}
+
+/// Entropy codes one block, in zigzag order.
+fn encode_block(
+    writer: &mut BitWriter,
+    coeffs: &[i16; 64],
+    prev_dc: &mut i16,
+    dc_codes: &HuffmanCodes,
+    ac_codes: &HuffmanCodes,
+) -> Result<(), Error> {
+    let out_of_range = Error::Format("Coefficient out of baseline range");
+    let (size, bits) = category(coeffs[0] as i32 - *prev_dc as i32);
+    if size > 11 {
+        return Err(out_of_range);
+    }
+    *prev_dc = coeffs[0];
+    let (code, length) = dc_codes.0[size as usize];
+    writer.write(code, length);
+    writer.write(bits, size);
+
+    let mut run = 0;
+    for &coeff in &coeffs[1..] {
+        if coeff == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 15 {
+            let (code, length) = ac_codes.0[0xF0];
+            writer.write(code, length);
+            run -= 16;
+        }
+        let (size, bits) = category(coeff as i32);
+        if size > 10 {
+            return Err(out_of_range);
+        }
+        let (code, length) = ac_codes.0[(run << 4 | size) as usize];
+        writer.write(code, length);
+        writer.write(bits, size);
+        run = 0;
+    }
+    if run > 0 {
+        let (code, length) = ac_codes.0[0x00];
+        writer.write(code, length);
+    }
+    Ok(())
+}
+
+/// Writes the quantized coefficients of `img`, in zigzag order, as a
+/// baseline JPEG. The first component is coded with the luminance Huffman
+/// tables, the others with the chrominance ones. `markers` are written
+/// right after SOI, as marker and payload pairs.
+pub fn write_coefficients(img: &CoefficientImage, restart_interval: u16, markers: &[(u8, &[u8])]) -> Result<Vec<u8>, Error> {
+    assert_eq!(img.order, CoefficientOrder::Zigzag, "Coefficients should be in zigzag order");
+    let components = &img.components;
+
+    // Distinct quantization tables, and the destination of each component
+    let mut tables: Vec<[u16; 64]> = Vec::new();
+    let mut destinations = Vec::new();
+    for component in components {
+        let destination = match tables.iter().position(|t| *t == component.quantization_table) {
+            Some(destination) => destination,
+            None => {
+                tables.push(component.quantization_table);
+                tables.len() - 1
+            }
+        };
+        destinations.push(destination as u8);
+    }
+    if tables.len() > 4 || tables.iter().flatten().any(|&q| q == 0 || q > 255) {
+        return Err(Error::Format("Quantization tables not baseline"));
+    }
+
+    // A single component is not interleaved: its MCU is one block
+    let factors: Vec<(usize, usize)> = if components.len() == 1 {
+        vec![(1, 1)]
+    } else {
+        components.iter().map(|c| (c.factors.0 as usize, c.factors.1 as usize)).collect()
+    };
+    let h_max = factors.iter().map(|f| f.0).max().unwrap();
+    let v_max = factors.iter().map(|f| f.1).max().unwrap();
+    let mcus_wide = (img.width as usize).div_ceil(8 * h_max);
+    let mcus_high = (img.height as usize).div_ceil(8 * v_max);
+
+    let dc_codes = [HuffmanCodes::new(&DC_LUMINANCE_BITS, &DC_VALUES), HuffmanCodes::new(&DC_CHROMINANCE_BITS, &DC_VALUES)];
+    let ac_codes = [
+        HuffmanCodes::new(&AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES),
+        HuffmanCodes::new(&AC_CHROMINANCE_BITS, &AC_CHROMINANCE_VALUES),
+    ];
+
+    let mut writer = BitWriter { data: Vec::new(), acc: 0, n_bits: 0 };
+    let mut prev_dc = vec![0; components.len()];
+    let n_mcus = mcus_wide * mcus_high;
+    for m in 0..n_mcus {
+        let (mx, my) = (m % mcus_wide, m / mcus_wide);
+        for (c, (component, &(h, v))) in components.iter().zip(&factors).enumerate() {
+            let t = usize::from(c > 0);
+            for by in 0..v {
+                for bx in 0..h {
+                    let block = component.block(mx * h + bx, my * v + by);
+                    encode_block(&mut writer, block, &mut prev_dc[c], &dc_codes[t], &ac_codes[t])?;
+                }
+            }
+        }
+
+        let interval = restart_interval as usize;
+        if interval > 0 && (m + 1) % interval == 0 && m + 1 < n_mcus {
+            writer.flush();
+            writer.data.extend([0xFF, 0xD0 + ((m + 1) / interval - 1) as u8 % 8]);
+            prev_dc.fill(0);
+        }
+    }
+    writer.flush();
+
+    let mut out = vec![0xFF, 0xD8];
+    for &(marker, payload) in markers {
+        segment(&mut out, marker, payload);
+    }
+    for (destination, table) in tables.iter().enumerate() {
+        segment(&mut out, 0xDB, &[&[destination as u8][..], &table.map(|q| q as u8)].concat());
+    }
+
+    let mut sof = vec![8];
+    sof.extend(img.height.to_be_bytes());
+    sof.extend(img.width.to_be_bytes());
+    sof.push(components.len() as u8);
+    for (component, destination) in components.iter().zip(&destinations) {
+        sof.extend([component.id, component.factors.0 << 4 | component.factors.1, *destination]);
+    }
+    segment(&mut out, 0xC0, &sof);
+
+    let huffman = [
+        (0x00, &DC_LUMINANCE_BITS, &DC_VALUES[..]),
+        (0x10, &AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES[..]),
+        (0x01, &DC_CHROMINANCE_BITS, &DC_VALUES[..]),
+        (0x11, &AC_CHROMINANCE_BITS, &AC_CHROMINANCE_VALUES[..]),
+    ];
+    for (class, bits, values) in huffman.iter().take(if components.len() == 1 { 2 } else { 4 }) {
+        segment(&mut out, 0xC4, &[&[*class][..], &bits[..], values].concat());
+    }
+
+    if restart_interval > 0 {
+        segment(&mut out, 0xDD, &restart_interval.to_be_bytes());
+    }
+
+    let mut sos = vec![components.len() as u8];
+    for (c, component) in components.iter().enumerate() {
+        sos.extend([component.id, if c == 0 { 0x00 } else { 0x11 }]);
+    }
+    sos.extend([0, 63, 0]);
+    segment(&mut out, 0xDA, &sos);
+
+    out.extend(writer.data);
+    out.extend([0xFF, 0xD9]);
+    Ok(out)
+}
+
+/// Encodes `pixels` as a baseline JPEG. One pair of `factors` gives a
+/// grayscale image, three a YCbCr one.
+#[cfg(test)]
+pub fn encode(pixels: &[Vec<[u8; 3]>], factors: &[(u8, u8)], quality: u8, restart_interval: u16) -> Vec<u8> {
+    let height = pixels.len();
+    let width = pixels[0].len();
+    let h_max = factors.iter().map(|f| f.0 as usize).max().unwrap();
+    let v_max = factors.iter().map(|f| f.1 as usize).max().unwrap();
+    // A single component is not interleaved: its MCU is one block
+    let (mcu_w, mcu_h) = if factors.len() == 1 { (8, 8) } else { (8 * h_max, 8 * v_max) };
+    let mcus_wide = width.div_ceil(mcu_w);
+    let mcus_high = height.div_ceil(mcu_h);
+
+    let planes: Vec<Vec<Vec<f32>>> = (0..factors.len())
+        .map(|c| {
+            pixels.iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|&[r, g, b]| {
+                            let (r, g, b) = (r as f32, g as f32, b as f32);
+                            match c {
+                                0 => 0.299 * r + 0.587 * g + 0.114 * b,
+                                1 => -0.168736 * r - 0.331264 * g + 0.5 * b + 128.,
+                                _ => 0.5 * r - 0.418688 * g - 0.081312 * b + 128.,
+                            }
+                        })
+                        .collect()
+                })
+                .collect()
+        })
+        .collect();
+
+    // Component sample at (x, y), averaged over the pixels it covers and
+    // repeating the image edges over the padding
+    let sample = |c: usize, x: usize, y: usize| {
+        if factors.len() == 1 {
+            return planes[0][y.min(height - 1)][x.min(width - 1)];
+        }
+        let (sx, sy) = (h_max / factors[c].0 as usize, v_max / factors[c].1 as usize);
+        let mut sum = 0.;
+        for dy in 0..sy {
+            for dx in 0..sx {
+                sum += planes[c][(y * sy + dy).min(height - 1)][(x * sx + dx).min(width - 1)];
+            }
+        }
+        sum / (sx * sy) as f32
+    };
+
+    let tables = [
+        scaled_table(&LUMINANCE_QUANTIZATION, quality),
+        scaled_table(&CHROMINANCE_QUANTIZATION, quality),
+    ];
+    let components = factors
+        .iter()
+        .enumerate()
+        .map(|(c, &(h, v))| {
+            let (h, v) = if factors.len() == 1 { (1, 1) } else { (h as usize, v as usize) };
+            let table = &tables[usize::from(c > 0)];
+            let (blocks_wide, blocks_high) = (mcus_wide * h, mcus_high * v);
+            let blocks = (0..blocks_wide * blocks_high)
+                .map(|b| {
+                    let (bx, by) = (b % blocks_wide, b / blocks_wide);
+                    let mut block = [[0.; 8]; 8];
+                    for (y, row) in block.iter_mut().enumerate() {
+                        for (x, value) in row.iter_mut().enumerate() {
+                            *value = sample(c, bx * 8 + x, by * 8 + y) - 128.;
+                        }
+                    }
+                    fdct(&block, table)
+                })
+                .collect();
+            ComponentCoefficients {
+                id: c as u8 + 1,
+                factors: factors[c],
+                quantization_table: table.map(|q| q as u16),
+                blocks_wide,
+                blocks_high,
+                blocks,
+            }
+        })
+        .collect();
+
+    let img = CoefficientImage { width: width as u16, height: height as u16, order: CoefficientOrder::Zigzag, components };
+    let jfif = b"JFIF\0\x01\x01\x00\x00\x01\x00\x01\x00\x00";
+    write_coefficients(&img, restart_interval, &[(0xE0, jfif)]).expect("Coefficients should be in range")
+}
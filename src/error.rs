@@ -0,0 +1,29 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The data ends before the segment being read.
+    Truncated,
+    /// Malformed or unsupported content.
+    Format(&'static str),
+    /// The output buffer cannot hold the decoded image.
+    BufferTooSmall,
+    /// The image goes beyond one of the `DecoderLimits`, named here.
+    LimitExceeded(&'static str),
+    /// An argument does not fit the image, such as a region outside of it.
+    InvalidArgument(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "Truncated JPEG data"),
+            Error::Format(msg) => write!(f, "Invalid JPEG data: {msg}"),
+            Error::BufferTooSmall => write!(f, "Output buffer too small"),
+            Error::LimitExceeded(limit) => write!(f, "Decoder limit exceeded: {limit}"),
+            Error::InvalidArgument(msg) => write!(f, "Invalid argument: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
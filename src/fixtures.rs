@@ -0,0 +1,23 @@
+//! Images generated for the tests and encoded by `encoder::encode`, so that
+//! any size, sampling factors and restart interval can be covered.
+
+use crate::encoder::encode;
+
+/// Smooth gradients with a sharper pattern over them.
+pub fn pattern(width: usize, height: usize) -> Vec<Vec<[u8; 3]>> {
+    (0..height)
+        .map(|y| {
+            (0..width)
+                .map(|x| {
+                    let stripe = if (x / 3 + y / 5) % 4 == 0 { 40 } else { 0 };
+                    [(x * 200 / width + stripe) as u8, (y * 220 / height) as u8, (60 + (x + 2 * y) % 96 + stripe) as u8]
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// `pattern(width, height)` as a baseline JPEG.
+pub fn fixture(width: usize, height: usize, factors: &[(u8, u8)], quality: u8, restart_interval: u16) -> Vec<u8> {
+    encode(&pattern(width, height), factors, quality, restart_interval)
+}
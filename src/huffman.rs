@@ -1,88 +1,85 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-
-#[derive(Debug)]
-pub struct Node {
-    symbol: Option<u8>,
-    left: Option<Rc<RefCell<Node>>>,
-    right: Option<Rc<RefCell<Node>>>,
+/// Decoding tables of a Huffman table, as in Annex F.2.2.3: the codes of a
+/// given length are consecutive, so a code is decoded by comparing it with
+/// the largest code of its length. Plain arrays, shared between threads and
+/// cloned cheaply.
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    /// Largest code of each length, from 1 to 16, or -1 if there is none.
+    max_code: [i32; 17],
+    /// Index in `symbols` of the codes of each length, minus their first code.
+    offset: [i32; 17],
+    /// Largest prefix of each length of a code, or -1 if there is none:
+    /// longer codes are rejected as soon as they go beyond it.
+    max_prefix: [i32; 17],
+    symbols: [u8; 256],
 }
 
-impl Node {
-    pub fn new() -> Self {
-        Node {
-            symbol: None,
-            left: None,
-            right: None,
+impl Lookup {
+    /// Tables for `counts[n]` codes of length `n + 1` and their `symbols`.
+    /// `None` if more codes of a length than fit or symbols than given.
+    pub fn new(counts: &[u8; 16], symbols: &[u8]) -> Option<Lookup> {
+        let total = counts.iter().map(|&n| n as usize).sum::<usize>();
+        if total > 256 || total > symbols.len() {
+            return None;
         }
-    }
-}
-
-#[derive(Debug)]
-pub struct Tree {
-    root: Rc<RefCell<Node>>,
-    curr_node: Rc<RefCell<Node>>,
-}
 
-impl Tree {
-    pub fn new() -> Tree {
-        let root = Rc::new(RefCell::new(Node::new()));
-        Tree {
-            root: root.clone(),
-            curr_node: root.clone(),
+        let mut lookup = Lookup { max_code: [-1; 17], offset: [0; 17], max_prefix: [-1; 17], symbols: [0; 256] };
+        lookup.symbols[..total].copy_from_slice(&symbols[..total]);
+        let mut code: i32 = 0;
+        let mut index = 0;
+        let mut last = (0, 0);
+        for (n, &count) in counts.iter().enumerate() {
+            let length = n + 1;
+            if count > 0 {
+                if (code + count as i32 - 1) >> length != 0 {
+                    return None;
+                }
+                lookup.offset[length] = index - code;
+                code += count as i32;
+                index += count as i32;
+                lookup.max_code[length] = code - 1;
+                last = (code - 1, length);
+            }
+            code <<= 1;
+        }
+        let (last_code, last_length) = last;
+        for length in 1..=last_length {
+            lookup.max_prefix[length] = last_code >> (last_length - length);
         }
+        Some(lookup)
     }
 
-    pub fn insert_code(&mut self, code: u16, length: u8, symbol: u8) {
-        // println!("c: {:b}, l: {}, s: {:x}", code, length, symbol);
-        let mut node = self.root.clone();
-        // Iterate from the most-significant bit (of the given length) down to 0.
-        for i in (0..length).rev() {
-            let bit = (code >> i) & 1;
-            if bit == 0 {
-                if node.borrow().left.is_none() {
-                    node.borrow_mut().left = Some(Rc::new(RefCell::new(Node::new())));
-                }
-                let next_node = node.borrow().left.as_ref().unwrap().clone();
-                node = next_node;
-            } else {
-                if node.borrow().right.is_none() {
-                    node.borrow_mut().right = Some(Rc::new(RefCell::new(Node::new())));
-                }
-                let next_node = node.borrow().right.as_ref().unwrap().clone();
-                node = next_node;
-            }
+    /// Symbol of the `length` bits of `code`, `None` if they only start a
+    /// code.
+    pub fn symbol(&self, code: i32, length: usize) -> Result<Option<u8>, &'static str> {
+        if length > 16 || code > self.max_prefix[length] {
+            return Err("Invalid code");
+        }
+        if code <= self.max_code[length] {
+            return Ok(Some(self.symbols[(self.offset[length] + code) as usize]));
         }
-        // At the leaf, store the symbol.
-        node.borrow_mut().symbol = Some(symbol);
+        Ok(None)
     }
 
-    pub fn forward(&mut self, bit: u8) -> Result<Option<u8>, &'static str>{
-        // Use a match to select the appropriate child.
-        let next = match bit {
-            0 => self.curr_node.borrow().left.clone(),
-            1 => self.curr_node.borrow().right.clone(),
-            _ => return Err("Must be 0 or 1"),
-        };
-    
-        // If a child exists, update curr_node; otherwise, return None.
-        if let Some(child) = next {
-            if let Some(value) = child.borrow().symbol {  
-                self.curr_node = self.root.clone();
-                Ok(Some(value))
-            } else {
-                self.curr_node = child.clone();
-                Ok(None)
+    /// Reads the next code of `bit_stream`. `None` at the end of the data,
+    /// at a marker or on an invalid code.
+    pub fn decode(&self, bit_stream: &mut BitStream) -> Option<u8> {
+        let mut code = 0;
+        for length in 1..=16 {
+            code = code << 1 | bit_stream.next_bit()? as i32;
+            match self.symbol(code, length) {
+                Ok(Some(symbol)) => return Some(symbol),
+                Ok(None) => {}
+                Err(_) => return None,
             }
-        } else {
-            Err("No child")
         }
+        None
     }
-    
 }
 
 
 #[allow(dead_code)]
+#[derive(Clone)]
 pub struct BitStream<'a> {
     data: &'a [u8],
     curr_byte: usize,
@@ -102,21 +99,19 @@ impl<'a> BitStream<'a> {
     }
 
     pub fn next_bit(&mut self) -> Option<u8> {
-        if self.pos >= self.data.len()*8-1{
-            // println!("End of scan");
-            return None;
+        let i = self.curr_byte;
+        if i > 0 && self.data.get(i) == Some(&0x00) && self.data[i-1] == 0xFF {
+            self.curr_byte += 1;
+            self.curr_bit = 8;
         }
 
         let i = self.curr_byte;
-        if self.data[i] == 0xFF && self.data[i+1] != 0x00 {
-            println!("Marker detected");
+        if i >= self.data.len() {
             return None;
         }
-        if  self.data[i] == 0x00 && self.data[i-1] == 0xFF {
-            // println!(" ");
-            // println!("0x00 detected after 0xFF");
-            self.curr_byte += 1;
-            self.curr_bit = 8;
+        if self.data[i] == 0xFF && self.data.get(i+1) != Some(&0x00) {
+            // Either a marker or a stuffed 0xFF whose 0x00 is not available yet
+            return None;
         }
         let byte = self.data[self.curr_byte];
 
@@ -129,29 +124,66 @@ impl<'a> BitStream<'a> {
         }
 
         self.pos = 8 * self.curr_byte + 7 - self.curr_bit as usize;
-        // print!("{res:01b}");
 
         Some(res)
     }
 
     pub fn get_coeff(&mut self, category: u8) -> i16 {
-        // println!(" ");
+        self.try_get_coeff(category).expect("Can't reach the next bit")
+    }
+
+    /// Same as `get_coeff`, returning `None` when the data runs out or on a
+    /// category too large for a 16-bit coefficient.
+    pub fn try_get_coeff(&mut self, category: u8) -> Option<i16> {
         if category == 0 {
-            return 0;
+            return Some(0);
+        }
+        if category > 15 {
+            return None;
         }
         let mut value = 0;
         for _ in 0..category {
             value <<= 1;
-            value += self.next_bit().expect("Can't reach the next bit") as i16;
+            value += self.next_bit()? as i16;
         }
         let vt = 1 << (category - 1);
         if value < vt {
             let vt = (-1) << category;
             value += vt + 1;
         }
-        // println!(" ");
 
-        value
+        Some(value)
+    }
+
+    /// Skips the padding bits of the current byte and the following RSTn
+    /// marker. Returns `false` if no restart marker is found there.
+    pub fn restart(&mut self) -> bool {
+        if self.curr_bit != 8 {
+            self.curr_bit = 8;
+            self.curr_byte += 1;
+        }
+        if self.curr_byte > 0 && self.data.get(self.curr_byte) == Some(&0x00) && self.data[self.curr_byte - 1] == 0xFF {
+            self.curr_byte += 1;
+        }
+        match self.data.get(self.curr_byte..self.curr_byte + 2) {
+            Some([0xFF, 0xD0..=0xD7]) => {
+                self.curr_byte += 2;
+                self.pos = 8 * self.curr_byte + 7 - self.curr_bit as usize;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Current byte and remaining bits in it, to resume reading later on.
+    pub fn position(&self) -> (usize, u8) {
+        (self.curr_byte, self.curr_bit)
+    }
+
+    pub fn seek(&mut self, byte: usize, bit: u8) {
+        self.curr_byte = byte;
+        self.curr_bit = bit;
+        self.pos = (8 * self.curr_byte + 7).saturating_sub(self.curr_bit as usize);
     }
 
     pub fn get_pos(&self) -> usize {
@@ -173,48 +205,42 @@ mod test {
 
         let mut bit_stream = BitStream::new(&bytes);
         let data = fs::read("img/white_square.jpg").expect("Failed to read image");
-        let tree = &mut parse(&data).huffman_tables[0].tree;
-        let mut value = 0;
-        for _ in 0..16 {
-            let bit = bit_stream.next_bit().unwrap();
-            if let Ok(Some(x)) = tree.forward(bit) {
-                value = bit_stream.get_coeff(x);
-                break
-            };
-        }
-        assert_eq!(value, 127);
-
-        for _ in 0..16 {
-            let bit = bit_stream.next_bit().unwrap();
-            if let Ok(Some(x)) = tree.forward(bit) {
-                value = bit_stream.get_coeff(x);
-                break
-            };
-        }
-        assert_eq!(value, -1);
+        let lookup = &parse(&data).huffman_tables[0].lookup;
+        let category = lookup.decode(&mut bit_stream).unwrap();
+        assert_eq!(bit_stream.get_coeff(category), 127);
+
+        let category = lookup.decode(&mut bit_stream).unwrap();
+        assert_eq!(bit_stream.get_coeff(category), -1);
     }
 
     #[test]
     fn test_huffman() {
         let data = fs::read("img/white_square.jpg").expect("Failed to read image");
-        let tree = &mut parse(&data).huffman_tables[0].tree;
-
-        assert_eq!(tree.forward(0), Ok(None));
-        assert_eq!(tree.forward(0), Ok(Some(0)));
-    
-        assert_eq!(tree.forward(0), Ok(None));
-        assert_eq!(tree.forward(1), Ok(None));
-        assert_eq!(tree.forward(0), Ok(Some(1)));
-    
-        for _ in 0..8 {
-            assert_eq!(tree.forward(1), Ok(None));
-        }
-        assert_eq!(tree.forward(0), Ok(Some(11)));
+        let lookup = &parse(&data).huffman_tables[0].lookup;
 
-        for _ in 0..8 {
-            assert_eq!(tree.forward(1), Ok(None));
+        assert_eq!(lookup.symbol(0b0, 1), Ok(None));
+        assert_eq!(lookup.symbol(0b00, 2), Ok(Some(0)));
+
+        assert_eq!(lookup.symbol(0b0, 1), Ok(None));
+        assert_eq!(lookup.symbol(0b01, 2), Ok(None));
+        assert_eq!(lookup.symbol(0b010, 3), Ok(Some(1)));
+
+        for length in 1..=8 {
+            assert_eq!(lookup.symbol((1 << length) - 1, length), Ok(None));
         }
-        assert_eq!(tree.forward(1), Err("No child"));
+        assert_eq!(lookup.symbol(0b111111110, 9), Ok(Some(11)));
+        assert_eq!(lookup.symbol(0b111111111, 9), Err("Invalid code"));
+
+        // Too many codes of length 1, too few symbols
+        assert!(Lookup::new(&[3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[0, 1, 2]).is_none());
+        assert!(Lookup::new(&[0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[0]).is_none());
+    }
+
+    #[test]
+    fn test_lookup_send_sync() {
+        fn check<T: Send + Sync>() {}
+        check::<Lookup>();
+        check::<crate::Decoder>();
     }
 
     #[test]
@@ -255,6 +281,24 @@ mod test {
         assert_eq!(bit_stream.next_bit(), None, "Next bit test");
     }
 
+    #[test]
+    fn test_bit_stream_restart() {
+        let bytes = [0b10100000, 0xFF, 0xD0, 0xFF, 0x00, 0xFF, 0xD1, 0b01000000];
+
+        let mut bit_stream = BitStream::new(&bytes);
+
+        assert_eq!(bit_stream.next_bit(), Some(1), "Next bit test");
+        assert_eq!(bit_stream.next_bit(), Some(0), "Next bit test");
+        assert!(bit_stream.restart(), "Restart test");
+        for _ in 0..8 {
+            assert_eq!(bit_stream.next_bit(), Some(1), "Next bit test");
+        }
+        assert!(bit_stream.restart(), "Restart test");
+        assert_eq!(bit_stream.next_bit(), Some(0), "Next bit test");
+        assert_eq!(bit_stream.next_bit(), Some(1), "Next bit test");
+        assert!(!bit_stream.restart(), "Restart test");
+    }
+
     #[test]
     fn test_bit_stream_get_coeff() {
         let bytes = [0b01110000, 0b10111110, 0b00001101];
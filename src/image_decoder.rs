@@ -0,0 +1,123 @@
+use std::io::{Cursor, Read};
+
+use image::error::{DecodingError, ImageError, ImageFormatHint, ImageResult};
+use image::{ColorType, ImageDecoder, ImageFormat};
+
+use crate::error::Error;
+use crate::info::{icc_profile, probe};
+use crate::limits::{check_limits, DecoderLimits};
+use crate::options::DecodeOptions;
+use crate::output::{decode_into_with, PixelFormat};
+
+fn decoding_error(err: Error) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Jpeg), err))
+}
+
+/// Adapter exposing this decoder through `image::ImageDecoder`, e.g. for
+/// `image::DynamicImage::from_decoder`. The whole file is read upfront.
+/// Grayscale images are decoded to `L8`, the others to `Rgb8`.
+pub struct JpegDecoder<R: Read> {
+    _reader: std::marker::PhantomData<R>,
+    data: Vec<u8>,
+    width: u16,
+    height: u16,
+    format: PixelFormat,
+    limits: DecoderLimits,
+}
+
+impl<R: Read> JpegDecoder<R> {
+    pub fn new(reader: R) -> ImageResult<JpegDecoder<R>> {
+        JpegDecoder::with_limits(reader, DecoderLimits::default())
+    }
+
+    pub fn with_limits(mut reader: R, limits: DecoderLimits) -> ImageResult<JpegDecoder<R>> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        let info = probe(&data).map_err(decoding_error)?;
+        check_limits(&data, &limits).map_err(decoding_error)?;
+        Ok(JpegDecoder {
+            _reader: std::marker::PhantomData,
+            data,
+            width: info.width,
+            height: info.height,
+            format: if info.components == 1 { PixelFormat::L8 } else { PixelFormat::Rgb8 },
+            limits,
+        })
+    }
+
+    fn decode_into(&self, buf: &mut [u8]) -> ImageResult<()> {
+        let options = DecodeOptions { limits: self.limits, ..Default::default() };
+        let stride = self.width as usize * self.format.bytes_per_pixel();
+        decode_into_with(&self.data, buf, self.format, stride, &options).map_err(decoding_error)
+    }
+}
+
+impl<'a, R: Read + 'a> ImageDecoder<'a> for JpegDecoder<R> {
+    type Reader = Cursor<Vec<u8>>;
+
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width as u32, self.height as u32)
+    }
+
+    fn color_type(&self) -> ColorType {
+        match self.format {
+            PixelFormat::L8 => ColorType::L8,
+            _ => ColorType::Rgb8,
+        }
+    }
+
+    fn icc_profile(&mut self) -> Option<Vec<u8>> {
+        icc_profile(&self.data)
+    }
+
+    fn into_reader(self) -> ImageResult<Self::Reader> {
+        let mut buf = vec![0; self.total_bytes() as usize];
+        self.decode_into(&mut buf)?;
+        Ok(Cursor::new(buf))
+    }
+
+    fn read_image(self, buf: &mut [u8]) -> ImageResult<()> {
+        self.decode_into(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use image::DynamicImage;
+    use crate::fixtures::fixture;
+    use super::*;
+
+    #[test]
+    fn test_image_decoder() {
+        let decoder = JpegDecoder::new(File::open("img/rec32dot.jpg").unwrap()).unwrap();
+        assert_eq!(decoder.dimensions(), (34, 16));
+        assert_eq!(decoder.color_type(), ColorType::Rgb8);
+
+        let img = DynamicImage::from_decoder(decoder).unwrap().to_rgb8();
+        let data = fs::read("img/rec32dot.jpg").unwrap();
+        let rgb = crate::decode_binary(&data).unwrap();
+        assert_eq!(img.get_pixel(33, 15).0, rgb[15][33]);
+    }
+
+    #[test]
+    fn test_image_decoder_grayscale() {
+        let data = fixture(21, 11, &[(1, 1)], 90, 0);
+        let decoder = JpegDecoder::new(&data[..]).unwrap();
+        assert_eq!(decoder.color_type(), ColorType::L8);
+
+        let img = DynamicImage::from_decoder(decoder).unwrap();
+        let luma = crate::decode(&data, PixelFormat::L8).unwrap();
+        assert_eq!(img.as_luma8().unwrap().as_raw(), &luma.data);
+    }
+
+    #[test]
+    fn test_image_decoder_error() {
+        let data = fs::read("img/rec32dot.jpg").unwrap();
+        assert!(JpegDecoder::new(&data[..50]).is_err());
+
+        let limits = DecoderLimits { max_width: 20, ..Default::default() };
+        assert!(JpegDecoder::with_limits(&data[..], limits).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::error::Error;
+use crate::parsing::{get_lenght, Application0, DensityUnit, StartOfFrame};
+
+#[derive(Debug, PartialEq)]
+pub enum ColorSpace {
+    Grayscale,
+    YCbCr,
+    Rgb,
+    Cmyk,
+    Ycck,
+}
+
+#[derive(Debug)]
+pub struct ImageInfo {
+    pub width: u16,
+    pub height: u16,
+    pub components: u8,
+    pub precision: u8,
+    /// Horizontal and vertical sampling factors of each component.
+    pub sampling_factors: Vec<(u8, u8)>,
+    pub progressive: bool,
+    pub color_space: ColorSpace,
+    /// Pixel density from the JFIF header, if any.
+    pub density: Option<(DensityUnit, (u16, u16))>,
+}
+
+/// Transform flag of an Adobe APP14 segment.
+fn adobe_transform(bytes: &[u8]) -> Option<u8> {
+    if bytes.len() >= 12 && bytes.starts_with(b"Adobe") {
+        Some(bytes[11])
+    } else {
+        None
+    }
+}
+
+fn color_space(sof: &StartOfFrame, ids: &[u8], adobe: Option<u8>) -> ColorSpace {
+    match (sof.components.len(), adobe) {
+        (1, _) => ColorSpace::Grayscale,
+        (4, Some(2)) => ColorSpace::Ycck,
+        (4, _) => ColorSpace::Cmyk,
+        (_, Some(0)) => ColorSpace::Rgb,
+        _ if ids == b"RGB" => ColorSpace::Rgb,
+        _ => ColorSpace::YCbCr,
+    }
+}
+
+/// ICC profile from the APP2 segments, whose chunks are reassembled in
+/// sequence order.
+pub fn icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks = Vec::new();
+
+    let mut i = 2;
+    while let Some(&[0xFF, marker, l1, l2]) = data.get(i..i + 4) {
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let length = get_lenght(&[l1, l2]);
+        let Some(bytes) = data.get(i + 4..i + 2 + length) else {
+            break;
+        };
+        if marker == 0xE2 && bytes.len() >= 14 && bytes.starts_with(b"ICC_PROFILE\0") {
+            chunks.push((bytes[12], &bytes[14..]));
+        }
+        i += 2 + length;
+    }
+
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|&(seq, _)| seq);
+    Some(chunks.into_iter().flat_map(|(_, chunk)| chunk.iter().copied()).collect())
+}
+
+/// Reads the image properties from the headers, stopping at the frame
+/// header: no table is built and no entropy-coded data is touched.
+pub fn probe(data: &[u8]) -> Result<ImageInfo, Error> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(Error::Format("Missing start of image"));
+    }
+
+    let mut density = None;
+    let mut adobe = None;
+    let mut i = 2;
+    loop {
+        let marker = match data.get(i..i + 2) {
+            Some([0xFF, 0xFF]) => {
+                i += 1;
+                continue;
+            }
+            Some(&[0xFF, marker]) => marker,
+            Some(_) => return Err(Error::Format("Expected a marker")),
+            None => return Err(Error::Truncated),
+        };
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            i += 2;
+            continue;
+        }
+
+        let length = get_lenght(data.get(i + 2..i + 4).ok_or(Error::Truncated)?);
+        if length < 2 {
+            return Err(Error::Format("Wrong segment length"));
+        }
+        let bytes = data.get(i + 4..i + 2 + length).ok_or(Error::Truncated)?;
+
+        match marker {
+            0xE0 if Application0::is_jfif(bytes) => {
+                let app0 = Application0::new(bytes);
+                density = Some((app0.density_unit, app0.density));
+            }
+            0xEE => adobe = adobe_transform(bytes),
+            0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                let sof = StartOfFrame::try_new(bytes).ok_or(Error::Format("Wrong frame header length"))?;
+                let ids: Vec<u8> = (0..sof.components.len()).map(|k| bytes[6 + 3 * k]).collect();
+                return Ok(ImageInfo {
+                    width: sof.width,
+                    height: sof.height,
+                    components: sof.components.len() as u8,
+                    precision: sof.precision,
+                    sampling_factors: sof.components.iter().map(|c| c.factors).collect(),
+                    progressive: matches!(marker, 0xC2 | 0xC6 | 0xCA | 0xCE),
+                    color_space: color_space(&sof, &ids, adobe),
+                    density,
+                });
+            }
+            0xDA | 0xD9 => return Err(Error::Format("Missing frame header")),
+            _ => {}
+        }
+        i += 2 + length;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn test_probe_baseline() {
+        let data = fs::read("img/maps.jpg").expect("Failed to read image");
+        let info = probe(&data).unwrap();
+
+        assert_eq!((info.width, info.height), (575, 418));
+        assert_eq!(info.components, 3);
+        assert_eq!(info.precision, 8);
+        assert_eq!(info.sampling_factors, [(2, 2), (1, 1), (1, 1)]);
+        assert!(!info.progressive);
+        assert_eq!(info.color_space, ColorSpace::YCbCr);
+        assert!(info.density.is_some());
+    }
+
+    #[test]
+    fn test_probe_progressive() {
+        let data = fs::read("img/carnaval.jpg").expect("Failed to read image");
+        let info = probe(&data).unwrap();
+
+        assert_eq!((info.width, info.height), (1200, 1200));
+        assert!(info.progressive);
+    }
+
+    #[test]
+    fn test_icc_profile() {
+        let data = fs::read("img/white_square.jpg").expect("Failed to read image");
+        assert_eq!(icc_profile(&data), None);
+
+        let mut with_icc = data[..2].to_vec();
+        for (seq, chunk) in [(2, b"world"), (1, b"hello")] {
+            with_icc.extend([0xFF, 0xE2, 0, 21]);
+            with_icc.extend(b"ICC_PROFILE\0");
+            with_icc.extend([seq, 2]);
+            with_icc.extend(chunk);
+        }
+        with_icc.extend(&data[2..]);
+        assert_eq!(icc_profile(&with_icc).unwrap(), b"helloworld");
+        assert!(probe(&with_icc).is_ok());
+    }
+
+    #[test]
+    fn test_probe_errors() {
+        let data = fs::read("img/white_square.jpg").expect("Failed to read image");
+
+        assert_eq!(probe(&data[..100]).unwrap_err(), Error::Truncated);
+        assert_eq!(probe(&data[1..]).unwrap_err(), Error::Format("Missing start of image"));
+        assert!(probe(&data[..200]).is_ok());
+    }
+}
@@ -2,41 +2,188 @@ mod parsing;
 mod huffman;
 mod scan;
 mod transf;
+mod stream;
+mod error;
+mod info;
+mod output;
+mod image_decoder;
+mod save;
+mod dump;
+mod coefficients;
+mod planes;
+mod options;
+mod conceal;
+mod limits;
+mod parallel;
+mod simd;
+mod decoder;
+mod metrics;
+mod quality;
+mod encoder;
+mod transform;
+#[cfg(test)]
+mod conformance;
+#[cfg(test)]
+mod fixtures;
 
-use std::fs;
-use image::{ImageBuffer, RgbImage};
+use std::{fs, io};
 
-pub fn get(img_path: &str) -> Vec<Vec<[u8; 3]>> {
-    let data: Vec<u8> = fs::read(img_path).unwrap();
-    let mut segments = parsing::parse(&data);
+pub use transf::{ColorConversion, Scale, Upsampling};
+pub use options::DecodeOptions;
+pub use decoder::Decoder;
+pub use conceal::{Concealment, LenientImage, Warning};
+pub use limits::{check_limits, DecoderLimits};
+pub use error::Error;
+pub use info::{icc_profile, probe, ColorSpace, ImageInfo};
+pub use image_decoder::JpegDecoder;
+pub use save::{save, write_image, Metadata, OutputFormat, SaveError};
+pub use coefficients::{read_coefficients, read_coefficients_with, CoefficientImage, CoefficientOrder, ComponentCoefficients};
+pub use planes::{decode_planes, decode_planes_with, Plane, PlanarImage};
+pub use quality::{estimate_quality, Encoder, QualityEstimate};
+pub use transform::{transform, transform_with, EdgeHandling, Transform};
+pub use metrics::{diff_heatmap, ms_ssim, mse, psnr, psnr_luma, quality_metrics, ssim, Metrics};
+pub use dump::{
+    dump_json, dump_text, marker_name, segments, Fields, FrameComponent, HuffmanFields,
+    QuantizationFields, ScanComponent, Segment,
+};
+pub use output::{decode, decode_into, decode_into_with, decode_with, DecodedImage, PixelFormat};
+pub use parsing::DensityUnit;
+pub use stream::{decode_reader, decode_reader_with, IncrementalDecoder, RowDecoder, Status};
 
-    let width = segments.start_of_frame.as_ref().unwrap().width;
-    let height = segments.start_of_frame.as_ref().unwrap().height;
+/// Reads and decodes the file at `img_path`, a decoding error being
+/// reported as `InvalidData`.
+pub fn get(img_path: &str) -> io::Result<Vec<Vec<[u8; 3]>>> {
+    let data = fs::read(img_path)?;
+    decode_binary(&data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
 
-    let res = transf::get_mcus(&mut segments);
-    transf::mcus_to_img(res, height, width)
+pub fn decode_binary(data: &[u8]) -> Result<Vec<Vec<[u8; 3]>>, Error> {
+    decode_binary_scaled(data, Scale::Full)
 }
 
-pub fn decode_binary(data: &[u8]) -> Vec<Vec<[u8; 3]>> {
-    let mut segments = parsing::parse(data);
+/// Decodes at 1/2, 1/4 or 1/8 of the full resolution, using reduced-size
+/// IDCTs instead of downscaling the full-size image.
+pub fn decode_binary_scaled(data: &[u8], scale: Scale) -> Result<Vec<Vec<[u8; 3]>>, Error> {
+    decode_binary_with(data, &DecodeOptions { scale, ..Default::default() })
+}
+
+/// Fails on headers the decoder cannot handle, on images beyond
+/// `options.limits` and on truncated scan data, see `decode_lenient` to
+/// decode the latter anyway.
+pub fn decode_binary_with(data: &[u8], options: &DecodeOptions) -> Result<Vec<Vec<[u8; 3]>>, Error> {
+    check_limits(data, &options.limits)?;
+    let segments = parsing::parse(data);
+    limits::validate(&segments, &options.limits)?;
+    let blocks = parallel::scan_blocks(&segments, options.parallel)?;
+    transf::blocks_to_image(&segments, &blocks, options.scale, options.upsampling, options.conversion, options.parallel)
+        .ok_or(Error::Truncated)
+}
+
+/// Decodes as much as possible of a truncated or corrupt image instead of
+/// giving up: decoding resumes at the next restart marker after corrupt
+/// data and the MCUs that could not be decoded are concealed, as listed in
+/// the returned warnings. Only fails if the headers are incomplete, invalid
+/// or go beyond `options.limits`.
+pub fn decode_lenient(data: &[u8], options: &DecodeOptions) -> Result<LenientImage, Error> {
+    let info = probe(data)?;
+    check_limits(data, &options.limits)?;
+    if info.progressive {
+        return Err(Error::Format("Progressive JPEG not supported"));
+    }
+    if parsing::headers_length(data).is_none() {
+        return Err(Error::Truncated);
+    }
+
+    let segments = parsing::parse(data);
+    limits::validate(&segments, &options.limits)?;
+    let (blocks, warnings) = conceal::scan_concealed(&segments, options.concealment)?;
+    let pixels = transf::blocks_to_image(&segments, &blocks, options.scale, options.upsampling, options.conversion, options.parallel)
+        .ok_or(Error::Truncated)?;
+    Ok(LenientImage { pixels, warnings })
+}
+
+/// Entry points of the fuzz targets in `fuzz/`, not part of the API.
+#[doc(hidden)]
+pub mod fuzzing {
+    use crate::limits::{self, check_limits, DecoderLimits};
+    use crate::parsing;
+
+    pub fn parse(data: &[u8]) {
+        let segments = parsing::parse(data);
+        let _ = parsing::validate(&segments);
+    }
+
+    pub fn scan_blocks(data: &[u8], limits: &DecoderLimits) {
+        let segments = parsing::parse(data);
+        if check_limits(data, limits).is_ok() && limits::validate(&segments, limits).is_ok() {
+            let _ = crate::scan::scan_blocks(&segments);
+        }
+    }
+}
+
+/// Decodes only the `w` x `h` pixels whose top-left corner is at (`x`, `y`).
+/// The rectangle is clipped to the image. Fails if it is empty or starts
+/// outside of the image.
+pub fn decode_region(data: &[u8], x: u16, y: u16, w: u16, h: u16) -> Result<Vec<Vec<[u8; 3]>>, Error> {
+    decode_region_with(data, x, y, w, h, &DecodeOptions::default())
+}
+
+/// Same as `decode_region`, with `options.upsampling`, `options.conversion`
+/// and `options.limits`. The other options are not used.
+pub fn decode_region_with(data: &[u8], x: u16, y: u16, w: u16, h: u16, options: &DecodeOptions) -> Result<Vec<Vec<[u8; 3]>>, Error> {
+    check_limits(data, &options.limits)?;
+    let segments = parsing::parse(data);
+    limits::validate(&segments, &options.limits)?;
 
     let width = segments.start_of_frame.as_ref().unwrap().width;
     let height = segments.start_of_frame.as_ref().unwrap().height;
+    if w == 0 || h == 0 {
+        return Err(Error::InvalidArgument("Empty region"));
+    }
+    if x >= width || y >= height {
+        return Err(Error::InvalidArgument("Region outside of the image"));
+    }
+    let w = w.min(width - x) as usize;
+    let h = h.min(height - y) as usize;
+    let (x, y) = (x as usize, y as usize);
 
-    let res = transf::get_mcus(&mut segments);
-    transf::mcus_to_img(res, height, width)
+    let layout = scan::McuLayout::new(segments.start_of_frame.as_ref().unwrap());
+    let (mcu_w, mcu_h) = layout.mcu_size(Scale::Full);
+    let cols = x / mcu_w..usize::div_ceil(x + w, mcu_w);
+    let rows = y / mcu_h..usize::div_ceil(y + h, mcu_h);
+    let res = transf::decode_region_mcus(&segments, cols.clone(), rows.clone(), options.upsampling, options.conversion)?;
+    Ok(transf::crop(res, x - cols.start * mcu_w, y - rows.start * mcu_h, w, h))
 }
 
-pub fn save(pic: Vec<Vec<[u8; 3]>>) {
-    let height = pic.len();
-    let width = pic[0].len();
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
 
-    let mut img: RgbImage = ImageBuffer::new(width as u32, height as u32);
+    #[test]
+    fn test_decode_region() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let full = decode_binary(&data).unwrap();
 
-    for (x, y, pixel) in img.enumerate_pixels_mut() {
-        let rgb = pic[y as usize][x as usize];
-        *pixel = image::Rgb(rgb);
+        let region = decode_region(&data, 13, 3, 20, 9).unwrap();
+        assert_eq!(region.len(), 9);
+        for (i, row) in region.iter().enumerate() {
+            assert_eq!(row[..], full[3 + i][13..33]);
+        }
+
+        let clipped = decode_region(&data, 30, 10, 100, 100).unwrap();
+        assert_eq!(clipped.len(), 6);
+        assert_eq!(clipped[5][..], full[15][30..34]);
+
+        let outside = Err(Error::InvalidArgument("Region outside of the image"));
+        assert_eq!(decode_region(&data, 34, 0, 1, 1), outside);
+        assert_eq!(decode_region(&data, 0, 16, 1, 1), outside);
+        let empty = Err(Error::InvalidArgument("Empty region"));
+        assert_eq!(decode_region(&data, 3, 3, 0, 5), empty);
+        assert_eq!(decode_region(&data, 3, 3, 5, 0), empty);
+        let scan_start = parsing::headers_length(&data).unwrap();
+        let truncated = &data[..(scan_start + data.len()) / 2];
+        assert_eq!(decode_region(truncated, 30, 10, 4, 6), Err(Error::Truncated));
+        assert!(decode_region(&data[..100], 0, 0, 1, 1).is_err());
     }
-    img.save("img/output.png").expect("Failed to save image");
 }
-
@@ -0,0 +1,187 @@
+use crate::dump::skip_entropy_data;
+use crate::error::Error;
+use crate::parsing::{self, get_lenght, Segments, StartOfFrame};
+use crate::scan::McuLayout;
+
+/// Bounds on what untrusted input may make the decoder do. The defaults
+/// accept any reasonable photograph. They do not bound the width and the
+/// height, so that long panoramas still decode: only `max_pixels`,
+/// `max_memory`, `max_scans` and `max_metadata` are enforced by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecoderLimits {
+    /// `u16::MAX` by default, the largest width a frame header can hold.
+    pub max_width: u16,
+    /// `u16::MAX` by default, the largest height a frame header can hold.
+    pub max_height: u16,
+    /// Bound on width x height.
+    pub max_pixels: u64,
+    /// Bound on the memory estimated to decode the frame, in bytes.
+    pub max_memory: u64,
+    /// Bound on the number of scans, i.e. of SOS segments.
+    pub max_scans: usize,
+    /// Bound on the total size of the APPn and COM segments, in bytes.
+    pub max_metadata: usize,
+}
+
+impl Default for DecoderLimits {
+    fn default() -> Self {
+        DecoderLimits {
+            max_width: u16::MAX,
+            max_height: u16::MAX,
+            max_pixels: 100_000_000,
+            max_memory: 1 << 30,
+            max_scans: 100,
+            max_metadata: 16 << 20,
+        }
+    }
+}
+
+impl DecoderLimits {
+    /// No limit at all, for trusted input.
+    pub fn none() -> DecoderLimits {
+        DecoderLimits {
+            max_width: u16::MAX,
+            max_height: u16::MAX,
+            max_pixels: u64::MAX,
+            max_memory: u64::MAX,
+            max_scans: usize::MAX,
+            max_metadata: usize::MAX,
+        }
+    }
+}
+
+/// Bytes needed to decode the whole frame at once: the coefficients and the
+/// inverse transformed samples of every block, plus the RGB output.
+pub fn memory_estimate(sof: &StartOfFrame) -> u64 {
+    let layout = McuLayout::new(sof);
+    let blocks = (layout.mcus_wide * layout.mcus_high * layout.blocks_per_mcu()) as u64;
+    let pixels = sof.width as u64 * sof.height as u64;
+    blocks * 64 * (2 + 4) + pixels * 3
+}
+
+fn check_frame(sof: &StartOfFrame, limits: &DecoderLimits) -> Result<(), Error> {
+    if sof.width > limits.max_width {
+        return Err(Error::LimitExceeded("width"));
+    }
+    if sof.height > limits.max_height {
+        return Err(Error::LimitExceeded("height"));
+    }
+    if sof.width as u64 * sof.height as u64 > limits.max_pixels {
+        return Err(Error::LimitExceeded("pixels"));
+    }
+    if memory_estimate(sof) > limits.max_memory {
+        return Err(Error::LimitExceeded("memory"));
+    }
+    Ok(())
+}
+
+/// `parsing::validate`, plus the frame limits: parsing goes on past the
+/// malformed data `check_limits` stops at, so the frame header it found may
+/// not have been checked yet.
+pub fn validate(segments: &Segments, limits: &DecoderLimits) -> Result<(), Error> {
+    parsing::validate(segments)?;
+    check_frame(segments.start_of_frame.as_ref().unwrap(), limits)
+}
+
+/// Walks the segments of `data`, checking each limit as soon as the frame
+/// header, a scan header or a metadata segment is reached. Malformed or
+/// truncated data stops the walk without error, it is up to the decoder to
+/// report it.
+pub fn check_limits(data: &[u8], limits: &DecoderLimits) -> Result<(), Error> {
+    let mut scans = 0;
+    let mut metadata = 0;
+
+    let mut i = 0;
+    while let Some(&[0xFF, marker]) = data.get(i..i + 2) {
+        match marker {
+            0xFF => {
+                i += 1;
+                continue;
+            }
+            0xD8 | 0x01 | 0xD0..=0xD7 => {
+                i += 2;
+                continue;
+            }
+            0xD9 => break,
+            _ => {}
+        }
+
+        let Some(length) = data.get(i + 2..i + 4).map(get_lenght) else {
+            break;
+        };
+        let Some(bytes) = data.get(i + 4..i + 2 + length) else {
+            break;
+        };
+        i += 2 + length;
+
+        match marker {
+            0xE0..=0xEF | 0xFE => {
+                metadata += length;
+                if metadata > limits.max_metadata {
+                    return Err(Error::LimitExceeded("metadata size"));
+                }
+            }
+            0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                let Some(sof) = StartOfFrame::try_new(bytes) else {
+                    break;
+                };
+                check_frame(&sof, limits)?;
+            }
+            0xDA => {
+                scans += 1;
+                if scans > limits.max_scans {
+                    return Err(Error::LimitExceeded("scans"));
+                }
+                i = skip_entropy_data(data, i).0;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn test_check_limits() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        assert_eq!(check_limits(&data, &DecoderLimits::default()), Ok(()));
+
+        let limits = DecoderLimits { max_width: 33, ..Default::default() };
+        assert_eq!(check_limits(&data, &limits), Err(Error::LimitExceeded("width")));
+        let limits = DecoderLimits { max_pixels: 34 * 16 - 1, ..Default::default() };
+        assert_eq!(check_limits(&data, &limits), Err(Error::LimitExceeded("pixels")));
+        let limits = DecoderLimits { max_memory: 1000, ..Default::default() };
+        assert_eq!(check_limits(&data, &limits), Err(Error::LimitExceeded("memory")));
+        let limits = DecoderLimits { max_scans: 0, ..Default::default() };
+        assert_eq!(check_limits(&data, &limits), Err(Error::LimitExceeded("scans")));
+        let limits = DecoderLimits { max_metadata: 10, ..Default::default() };
+        assert_eq!(check_limits(&data, &limits), Err(Error::LimitExceeded("metadata size")));
+
+        // Frame header claiming 65535x65535, without any scan data
+        let mut huge = data[..2].to_vec();
+        huge.extend([0xFF, 0xC0, 0, 17, 8, 0xFF, 0xFF, 0xFF, 0xFF, 3, 1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1]);
+        assert_eq!(check_limits(&huge, &DecoderLimits::default()), Err(Error::LimitExceeded("pixels")));
+        assert_eq!(check_limits(&huge, &DecoderLimits::none()), Ok(()));
+        assert_eq!(crate::decode(&huge, crate::PixelFormat::Rgb8).unwrap_err(), Error::LimitExceeded("pixels"));
+        assert_eq!(crate::read_coefficients(&huge, crate::CoefficientOrder::Zigzag).unwrap_err(), Error::LimitExceeded("pixels"));
+        assert_eq!(crate::decode_binary(&huge).unwrap_err(), Error::LimitExceeded("pixels"));
+        assert_eq!(crate::decode_region(&huge, 0, 0, 8, 8).unwrap_err(), Error::LimitExceeded("pixels"));
+        assert!(matches!(crate::RowDecoder::new(&huge), Err(Error::LimitExceeded("pixels"))));
+
+        // Every entry point takes the limits
+        let options = crate::DecodeOptions { limits: DecoderLimits { max_width: 33, ..Default::default() }, ..Default::default() };
+        assert_eq!(crate::decode_binary_with(&data, &options).unwrap_err(), Error::LimitExceeded("width"));
+        assert_eq!(crate::decode_region_with(&data, 0, 0, 8, 8, &options).unwrap_err(), Error::LimitExceeded("width"));
+        assert_eq!(crate::read_coefficients_with(&data, crate::CoefficientOrder::Zigzag, &options.limits).unwrap_err(), Error::LimitExceeded("width"));
+        assert_eq!(crate::decode_planes_with(&data, &options.limits).unwrap_err(), Error::LimitExceeded("width"));
+        assert!(matches!(crate::RowDecoder::with_options(&data, &options), Err(Error::LimitExceeded("width"))));
+
+        let limits = DecoderLimits { max_height: 15, ..Default::default() };
+        let mut decoder = crate::IncrementalDecoder::with_limits(limits);
+        assert_eq!(decoder.feed(&data), crate::Status::Error(Error::LimitExceeded("height")));
+    }
+}
@@ -1,15 +1,349 @@
-use jpeg::{get, save};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::{env, fs};
 
+use jpeg::{
+    decode_binary_with, decode_lenient, diff_heatmap, dump_json, dump_text, estimate_quality, probe,
+    quality_metrics, save, segments, transform, write_image, ColorConversion, DecodeOptions, Decoder, EdgeHandling, Encoder, Fields, Metadata,
+    OutputFormat, Scale, Transform, Upsampling,
+};
 
-fn main() {
-    // let img_path = "img/white_square.jpg";
-    // let img_path = "img/white_square_16x16.jpg";
-    // let img_path = "img/sq16rdot.jpg";
-    // let img_path = "img/rec32dot.jpg";
-    let img_path = "img/maps.jpg";
+const USAGE: &str = "Usage:
+    jpeg decode <in> -o <out> [--scale 1|2|4|8] [--upsampling fancy|nearest] [--color float|fixed] [--lenient] [--parallel]
+    jpeg info <in>
+    jpeg dump <in> [--json]
+    jpeg batch <dir> [-o <out dir>] [--format png|ppm|pgm|bmp|raw] [--lenient]
+    jpeg compare <a> <b> [--heatmap <out>]
+    jpeg transform <in> -o <out> (--rotate 90|180|270 | --flip h|v | --transpose | --transverse) [--trim | --perfect]";
 
-    let pic = get(img_path);
-    save(pic);
+type CliResult = Result<(), Box<dyn Error>>;
 
+/// Wrong arguments, reported along with the usage.
+#[derive(Debug)]
+struct UsageError(String);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+fn usage(msg: &str) -> Box<dyn Error> {
+    Box::new(UsageError(msg.to_string()))
+}
+
+/// Value following `flag` in `args`, if present.
+fn option<'a>(args: &'a [String], flag: &str) -> Result<Option<&'a str>, Box<dyn Error>> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) => match args.get(i + 1) {
+            Some(value) => Ok(Some(value)),
+            None => Err(usage(&format!("Missing value after {flag}"))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_scale(value: Option<&str>) -> Result<Scale, Box<dyn Error>> {
+    match value {
+        None | Some("1") => Ok(Scale::Full),
+        Some("2") => Ok(Scale::Half),
+        Some("4") => Ok(Scale::Quarter),
+        Some("8") => Ok(Scale::Eighth),
+        Some(other) => Err(usage(&format!("Wrong scale: {other}"))),
+    }
+}
+
+fn parse_conversion(value: Option<&str>) -> Result<ColorConversion, Box<dyn Error>> {
+    match value {
+        None | Some("float") => Ok(ColorConversion::Float),
+        Some("fixed") => Ok(ColorConversion::FixedPoint),
+        Some(other) => Err(usage(&format!("Wrong colour conversion: {other}"))),
+    }
+}
+
+fn parse_upsampling(value: Option<&str>) -> Result<Upsampling, Box<dyn Error>> {
+    match value {
+        None | Some("fancy") => Ok(Upsampling::Fancy),
+        Some("nearest") => Ok(Upsampling::Nearest),
+        Some(other) => Err(usage(&format!("Wrong upsampling: {other}"))),
+    }
 }
 
+/// Decodes and saves one file. In lenient mode, what was concealed is
+/// reported on stderr.
+fn convert(input: &Path, output: &Path, format: OutputFormat, options: &DecodeOptions, lenient: bool) -> CliResult {
+    let data = fs::read(input)?;
+    let pic = if lenient {
+        let img = decode_lenient(&data, options)?;
+        for warning in &img.warnings {
+            eprintln!("{}: warning: {warning}", input.display());
+        }
+        img.pixels
+    } else {
+        decode_binary_with(&data, options)?
+    };
+
+    let file = std::io::BufWriter::new(fs::File::create(output)?);
+    write_image(&pic, file, format, &Metadata::read(&data))?;
+    Ok(())
+}
+
+fn decode(args: &[String]) -> CliResult {
+    let input = args.first().ok_or_else(|| usage("Missing input file"))?;
+    let output = option(args, "-o")?.ok_or_else(|| usage("Missing output file"))?;
+    let options = DecodeOptions {
+        scale: parse_scale(option(args, "--scale")?)?,
+        upsampling: parse_upsampling(option(args, "--upsampling")?)?,
+        conversion: parse_conversion(option(args, "--color")?)?,
+        parallel: args.iter().any(|arg| arg == "--parallel"),
+        ..Default::default()
+    };
+    let lenient = args.iter().any(|arg| arg == "--lenient");
+    let format = OutputFormat::from_path(output).ok_or_else(|| usage("Unknown output format"))?;
+    convert(Path::new(input), Path::new(output), format, &options, lenient)
+}
+
+fn info(args: &[String]) -> CliResult {
+    let input = args.first().ok_or_else(|| usage("Missing input file"))?;
+    let data = fs::read(input)?;
+    let info = probe(&data)?;
+
+    println!("File:            {input}");
+    println!("Dimensions:      {}x{}", info.width, info.height);
+    println!("Components:      {}", info.components);
+    println!("Precision:       {} bits", info.precision);
+    println!("Sampling:        {:?}", info.sampling_factors);
+    println!("Mode:            {}", if info.progressive { "progressive" } else { "baseline" });
+    println!("Color space:     {:?}", info.color_space);
+    if let Some((unit, (x, y))) = info.density {
+        println!("Density:         {x}x{y} ({unit:?})");
+    }
+
+    if let Ok(estimate) = estimate_quality(&data) {
+        if estimate.standard {
+            println!("Quality:         {} (standard tables)", estimate.quality);
+        } else {
+            println!("Quality:         ~{} (custom tables)", estimate.quality);
+        }
+        let encoder = match estimate.encoder {
+            Some(Encoder::Photoshop) => "Photoshop".to_string(),
+            Some(Encoder::Libjpeg) => "libjpeg or compatible".to_string(),
+            Some(Encoder::Camera { make, model }) => [Some(make), model].into_iter().flatten().collect::<Vec<_>>().join(" "),
+            Some(Encoder::Software(name)) => name,
+            None => "unknown".to_string(),
+        };
+        println!("Encoder:         {encoder}");
+    }
+
+    let (segments, error) = segments(&data);
+    for segment in &segments {
+        match &segment.fields {
+            Fields::Frame { components, .. } => {
+                for c in components {
+                    println!(
+                        "Component {}:     sampling {}x{}, quantization table {}",
+                        c.id, c.factors.0, c.factors.1, c.quantization_table
+                    );
+                }
+            }
+            Fields::Quantization(tables) => {
+                for t in tables {
+                    println!("Quantization {}:  {} bits", t.id, t.precision);
+                    for row in &t.table {
+                        println!("                {}", row.map(|v| format!("{v:>4}")).join(""));
+                    }
+                }
+            }
+            Fields::Huffman(tables) => {
+                for t in tables {
+                    let class = if t.class == 0 { "DC" } else { "AC" };
+                    let lengths: Vec<String> = t.code_lengths.iter().map(|n| n.to_string()).collect();
+                    println!("Huffman {class} {}:    {} codes, lengths {}", t.id, t.symbols.len(), lengths.join(" "));
+                }
+            }
+            Fields::Scan { components, ss, se, ah, al, .. } => {
+                let tables: Vec<String> = components.iter().map(|c| format!("{} (DC {}, AC {})", c.id, c.dc_table, c.ac_table)).collect();
+                println!("Scan:            components {}; Ss {ss}, Se {se}, Ah {ah}, Al {al}", tables.join(", "));
+            }
+            Fields::RestartInterval(interval) => println!("Restart:         every {interval} MCUs"),
+            Fields::Comment(comment) => println!("Comment:         {comment:?}"),
+            Fields::Application { identifier } if !identifier.is_empty() => {
+                println!("{:<17}{identifier}", format!("{}:", segment.name()));
+            }
+            _ => {}
+        }
+    }
+    let markers: Vec<&str> = segments.iter().map(|s| s.name()).collect();
+    println!("Markers:         {}", markers.join(" "));
+    if let Some(error) = error {
+        println!("Error:           {error}");
+    }
+    Ok(())
+}
+
+fn dump(args: &[String]) -> CliResult {
+    let input = args.first().ok_or_else(|| usage("Missing input file"))?;
+    let data = fs::read(input)?;
+
+    if args.iter().any(|arg| arg == "--json") {
+        print!("{}", dump_json(&data));
+    } else {
+        print!("{}", dump_text(&data));
+    }
+    Ok(())
+}
+
+/// Output file of `input` in `out_dir`: its whole file name followed by
+/// `ext`, so that `a.jpg` and `a.jpeg` or `a.b.jpg` and `a.c.jpg` do not
+/// overwrite each other.
+fn batch_output(out_dir: &Path, input: &Path, ext: &str) -> PathBuf {
+    let mut name = input.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(ext);
+    out_dir.join(name)
+}
+
+fn batch(args: &[String]) -> CliResult {
+    let dir = args.first().ok_or_else(|| usage("Missing input directory"))?;
+    let out_dir = option(args, "-o")?.map(PathBuf::from).unwrap_or_else(|| PathBuf::from(dir));
+    let format = match option(args, "--format")? {
+        Some(ext) => OutputFormat::from_path(format!("x.{ext}")).ok_or_else(|| usage("Unknown output format"))?,
+        None => OutputFormat::Png,
+    };
+    let lenient = args.iter().any(|arg| arg == "--lenient");
+    fs::create_dir_all(&out_dir)?;
+
+    let mut inputs: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+            matches!(ext.as_deref(), Some("jpg" | "jpeg"))
+        })
+        .collect();
+    inputs.sort();
+
+    let mut failures = 0;
+    for input in &inputs {
+        let output = batch_output(&out_dir, input, format.extension());
+        match convert(input, &output, format, &DecodeOptions::default(), lenient) {
+            Ok(()) => println!("{} -> {}", input.display(), output.display()),
+            Err(err) => {
+                eprintln!("{}: {err}", input.display());
+                failures += 1;
+            }
+        }
+    }
+    println!("{} converted, {failures} failed", inputs.len() - failures);
+
+    if failures > 0 {
+        return Err(format!("{failures} file(s) failed").into());
+    }
+    Ok(())
+}
+
+fn compare(args: &[String]) -> CliResult {
+    let [a, b] = [args.first(), args.get(1)].map(|arg| arg.filter(|arg| !arg.starts_with('-')));
+    let (a, b) = a.zip(b).ok_or_else(|| usage("Missing input files"))?;
+    let heatmap = option(args, "--heatmap")?;
+    if heatmap.is_some_and(|out| OutputFormat::from_path(out).is_none()) {
+        return Err(usage("Unknown output format"));
+    }
+
+    let mut decoder = Decoder::new();
+    let mut images = Vec::new();
+    for input in [a, b] {
+        let data = fs::read(input)?;
+        images.push(decoder.decode(&data).map_err(|err| format!("{input}: {err}"))?);
+    }
+
+    let metrics = quality_metrics(&images[0], &images[1])?;
+    let [red, green, blue] = metrics.mse;
+    println!("MSE:       R {red:.3}  G {green:.3}  B {blue:.3}");
+    let [red, green, blue] = metrics.psnr;
+    println!("PSNR:      R {red:.2} dB  G {green:.2} dB  B {blue:.2} dB  Y {:.2} dB", metrics.psnr_luma);
+    println!("SSIM:      {:.5}", metrics.ssim);
+    println!("MS-SSIM:   {:.5}", metrics.ms_ssim);
+
+    if let Some(out) = heatmap {
+        save(&diff_heatmap(&images[0], &images[1])?, out, &Metadata::default())?;
+        println!("Heatmap:   {out}");
+    }
+    Ok(())
+}
+
+fn parse_transform(args: &[String]) -> Result<Transform, Box<dyn Error>> {
+    let mut transforms = Vec::new();
+    match option(args, "--rotate")? {
+        None => {}
+        Some("90") => transforms.push(Transform::Rotate90),
+        Some("180") => transforms.push(Transform::Rotate180),
+        Some("270") => transforms.push(Transform::Rotate270),
+        Some(other) => return Err(usage(&format!("Wrong rotation: {other}"))),
+    }
+    match option(args, "--flip")? {
+        None => {}
+        Some("h") => transforms.push(Transform::FlipHorizontal),
+        Some("v") => transforms.push(Transform::FlipVertical),
+        Some(other) => return Err(usage(&format!("Wrong flip: {other}"))),
+    }
+    if args.iter().any(|arg| arg == "--transpose") {
+        transforms.push(Transform::Transpose);
+    }
+    if args.iter().any(|arg| arg == "--transverse") {
+        transforms.push(Transform::Transverse);
+    }
+    match transforms[..] {
+        [transform] => Ok(transform),
+        [] => Err(usage("Missing transform")),
+        _ => Err(usage("Only one transform at a time")),
+    }
+}
+
+fn transform_file(args: &[String]) -> CliResult {
+    let input = args.first().ok_or_else(|| usage("Missing input file"))?;
+    let output = option(args, "-o")?.ok_or_else(|| usage("Missing output file"))?;
+    let edges = match (args.iter().any(|arg| arg == "--trim"), args.iter().any(|arg| arg == "--perfect")) {
+        (false, false) => EdgeHandling::Keep,
+        (true, false) => EdgeHandling::Trim,
+        (false, true) => EdgeHandling::Perfect,
+        (true, true) => return Err(usage("Either --trim or --perfect")),
+    };
+    let kind = parse_transform(args)?;
+
+    let data = fs::read(input)?;
+    fs::write(output, transform(&data, kind, edges)?)?;
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let res = match args.first().map(String::as_str) {
+        Some("decode") => decode(&args[1..]),
+        Some("info") => info(&args[1..]),
+        Some("dump") => dump(&args[1..]),
+        Some("batch") => batch(&args[1..]),
+        Some("compare") => compare(&args[1..]),
+        Some("transform") => transform_file(&args[1..]),
+        Some("-h" | "--help") => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        _ => Err(usage("Missing or unknown command")),
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) if err.is::<UsageError>() => {
+            eprintln!("{err}\n\n{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Full-reference quality metrics between two decoded images of the same
+//! size, such as an original and its re-encoded version. SSIM and MS-SSIM
+//! are computed on the luma, with the 11x11 Gaussian window (sigma 1.5) and
+//! constants of Wang et al. Every function fails with
+//! `Error::InvalidArgument` on empty images, rows of different widths or
+//! images of different sizes.
+
+use crate::error::Error;
+
+const LUMA: [f64; 3] = [0.299, 0.587, 0.114];
+const K1: f64 = 0.01;
+const K2: f64 = 0.03;
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+/// Every metric between two images. PSNRs are in dB, infinite for
+/// identical images.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metrics {
+    /// Per RGB channel.
+    pub mse: [f64; 3],
+    /// Per RGB channel.
+    pub psnr: [f64; 3],
+    pub psnr_luma: f64,
+    pub ssim: f64,
+    pub ms_ssim: f64,
+}
+
+/// Samples of one channel.
+struct Channel {
+    width: usize,
+    height: usize,
+    values: Vec<f64>,
+}
+
+impl Channel {
+    fn new(img: &[Vec<[u8; 3]>], value: impl Fn([u8; 3]) -> f64) -> Channel {
+        Channel {
+            width: img[0].len(),
+            height: img.len(),
+            values: img.iter().flatten().map(|&pixel| value(pixel)).collect(),
+        }
+    }
+
+    fn luma(img: &[Vec<[u8; 3]>]) -> Channel {
+        Channel::new(img, |pixel| (0..3).map(|c| LUMA[c] * pixel[c] as f64).sum())
+    }
+
+    /// Product of the samples of both channels.
+    fn product(&self, other: &Channel) -> Channel {
+        let values = self.values.iter().zip(&other.values).map(|(a, b)| a * b).collect();
+        Channel { values, ..*self }
+    }
+
+    /// Filtered by `window` along both directions, where the window fits
+    /// entirely in the channel.
+    fn filter(&self, window: &[f64]) -> Channel {
+        let n = window.len();
+        let (width, height) = (self.width + 1 - n, self.height + 1 - n);
+        let dot = |taps: &[f64]| window.iter().zip(taps).map(|(w, v)| w * v).sum::<f64>();
+
+        let mut rows = Vec::with_capacity(width * self.height);
+        for row in self.values.chunks_exact(self.width) {
+            rows.extend(row.windows(n).map(dot));
+        }
+        let mut values = Vec::with_capacity(width * height);
+        let mut taps = vec![0.; n];
+        for y in 0..height {
+            for x in 0..width {
+                for (k, tap) in taps.iter_mut().enumerate() {
+                    *tap = rows[(y + k) * width + x];
+                }
+                values.push(dot(&taps));
+            }
+        }
+        Channel { width, height, values }
+    }
+
+    /// Half the size, each sample the average of 2x2 ones.
+    fn downsampled(&self) -> Channel {
+        let (width, height) = (self.width / 2, self.height / 2);
+        let at = |x: usize, y: usize| self.values[y * self.width + x];
+        let values = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (y, x)))
+            .map(|(y, x)| (at(2 * x, 2 * y) + at(2 * x + 1, 2 * y) + at(2 * x, 2 * y + 1) + at(2 * x + 1, 2 * y + 1)) / 4.)
+            .collect();
+        Channel { width, height, values }
+    }
+}
+
+fn check_sizes(a: &[Vec<[u8; 3]>], b: &[Vec<[u8; 3]>]) -> Result<(), Error> {
+    if a.is_empty() || a[0].is_empty() || b.is_empty() {
+        return Err(Error::InvalidArgument("Empty image"));
+    }
+    let width = a[0].len();
+    if a.len() != b.len() || b[0].len() != width {
+        return Err(Error::InvalidArgument("Images of different sizes"));
+    }
+    if !a.iter().chain(b).all(|row| row.len() == width) {
+        return Err(Error::InvalidArgument("Rows of different widths"));
+    }
+    Ok(())
+}
+
+fn psnr_of(mse: f64) -> f64 {
+    10. * (255f64.powi(2) / mse).log10()
+}
+
+/// Mean squared error of each RGB channel.
+pub fn mse(a: &[Vec<[u8; 3]>], b: &[Vec<[u8; 3]>]) -> Result<[f64; 3], Error> {
+    check_sizes(a, b)?;
+    let mut sums = [0.; 3];
+    for (pa, pb) in a.iter().flatten().zip(b.iter().flatten()) {
+        for (sum, (&va, &vb)) in sums.iter_mut().zip(pa.iter().zip(pb)) {
+            *sum += (va as f64 - vb as f64).powi(2);
+        }
+    }
+    let n = (a.len() * a[0].len()) as f64;
+    Ok(sums.map(|sum| sum / n))
+}
+
+/// PSNR of each RGB channel.
+pub fn psnr(a: &[Vec<[u8; 3]>], b: &[Vec<[u8; 3]>]) -> Result<[f64; 3], Error> {
+    Ok(mse(a, b)?.map(psnr_of))
+}
+
+/// PSNR of the luma, computed from the RGB pixels as for JPEG.
+pub fn psnr_luma(a: &[Vec<[u8; 3]>], b: &[Vec<[u8; 3]>]) -> Result<f64, Error> {
+    check_sizes(a, b)?;
+    let (la, lb) = (Channel::luma(a), Channel::luma(b));
+    let squares: f64 = la.values.iter().zip(&lb.values).map(|(x, y)| (x - y).powi(2)).sum();
+    Ok(psnr_of(squares / la.values.len() as f64))
+}
+
+/// Mean SSIM and mean contrast-structure term over the windows of `a` and
+/// `b`. The window shrinks to fit channels smaller than 11 samples.
+fn ssim_terms(a: &Channel, b: &Channel) -> (f64, f64) {
+    let n = 11.min(a.width).min(a.height);
+    let center = (n - 1) as f64 / 2.;
+    let window: Vec<f64> = (0..n).map(|i| (-(i as f64 - center).powi(2) / (2. * 1.5 * 1.5)).exp()).collect();
+    let total: f64 = window.iter().sum();
+    let window: Vec<f64> = window.iter().map(|w| w / total).collect();
+
+    let (mu_a, mu_b) = (a.filter(&window), b.filter(&window));
+    let (aa, bb, ab) = (a.product(a).filter(&window), b.product(b).filter(&window), a.product(b).filter(&window));
+    let (c1, c2) = ((K1 * 255.).powi(2), (K2 * 255.).powi(2));
+
+    let (mut ssim, mut cs) = (0., 0.);
+    for i in 0..mu_a.values.len() {
+        let (ma, mb) = (mu_a.values[i], mu_b.values[i]);
+        let var_a = aa.values[i] - ma * ma;
+        let var_b = bb.values[i] - mb * mb;
+        let cov = ab.values[i] - ma * mb;
+        let contrast_structure = (2. * cov + c2) / (var_a + var_b + c2);
+        let luminance = (2. * ma * mb + c1) / (ma * ma + mb * mb + c1);
+        ssim += luminance * contrast_structure;
+        cs += contrast_structure;
+    }
+    let count = mu_a.values.len() as f64;
+    (ssim / count, cs / count)
+}
+
+/// Structural similarity of the luma, 1 for identical images.
+pub fn ssim(a: &[Vec<[u8; 3]>], b: &[Vec<[u8; 3]>]) -> Result<f64, Error> {
+    check_sizes(a, b)?;
+    Ok(ssim_terms(&Channel::luma(a), &Channel::luma(b)).0)
+}
+
+/// Multi-scale SSIM of the luma over 5 scales, each half the size of the
+/// previous one. Images too small for all of them use the scales at least
+/// 11 samples wide and high, the weights being normalized over these.
+pub fn ms_ssim(a: &[Vec<[u8; 3]>], b: &[Vec<[u8; 3]>]) -> Result<f64, Error> {
+    check_sizes(a, b)?;
+    let (mut la, mut lb) = (Channel::luma(a), Channel::luma(b));
+    let mut scales = 1;
+    while scales < MS_SSIM_WEIGHTS.len() && la.width.min(la.height) >> scales >= 11 {
+        scales += 1;
+    }
+    let weights = &MS_SSIM_WEIGHTS[..scales];
+    let total: f64 = weights.iter().sum();
+
+    let mut res = 1.;
+    for (j, weight) in weights.iter().enumerate() {
+        let (ssim, cs) = ssim_terms(&la, &lb);
+        // Negative terms are possible for very different images
+        if j + 1 == scales {
+            res *= ssim.max(0.).powf(weight / total);
+        } else {
+            res *= cs.max(0.).powf(weight / total);
+            (la, lb) = (la.downsampled(), lb.downsampled());
+        }
+    }
+    Ok(res)
+}
+
+/// Every metric between `a` and `b`.
+pub fn quality_metrics(a: &[Vec<[u8; 3]>], b: &[Vec<[u8; 3]>]) -> Result<Metrics, Error> {
+    let mse = mse(a, b)?;
+    Ok(Metrics {
+        mse,
+        psnr: mse.map(psnr_of),
+        psnr_luma: psnr_luma(a, b)?,
+        ssim: ssim(a, b)?,
+        ms_ssim: ms_ssim(a, b)?,
+    })
+}
+
+/// Largest channel difference of each pixel, from black for none through
+/// red and yellow to white for the largest one of the image.
+pub fn diff_heatmap(a: &[Vec<[u8; 3]>], b: &[Vec<[u8; 3]>]) -> Result<Vec<Vec<[u8; 3]>>, Error> {
+    check_sizes(a, b)?;
+    let diffs: Vec<Vec<u8>> = a
+        .iter()
+        .zip(b)
+        .map(|(ra, rb)| ra.iter().zip(rb).map(|(pa, pb)| (0..3).map(|c| pa[c].abs_diff(pb[c])).max().unwrap()).collect())
+        .collect();
+    let max = diffs.iter().flatten().copied().max().unwrap_or(0).max(1) as f64;
+
+    let color = |diff: u8| {
+        let t = 3. * diff as f64 / max;
+        [t, t - 1., t - 2.].map(|v| (v.clamp(0., 1.) * 255.).round() as u8)
+    };
+    Ok(diffs.iter().map(|row| row.iter().map(|&diff| color(diff)).collect()).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::encoder::encode;
+    use crate::fixtures;
+    use crate::decode_binary;
+    use super::*;
+
+    fn pattern() -> Vec<Vec<[u8; 3]>> {
+        fixtures::pattern(128, 96)
+    }
+
+    #[test]
+    fn test_mse_psnr() {
+        let a = pattern();
+        let mut b = a.clone();
+        for pixel in b.iter_mut().flatten() {
+            pixel[0] = pixel[0].saturating_add(10);
+        }
+        assert_eq!(mse(&a, &a).unwrap(), [0.; 3]);
+        assert_eq!(psnr(&a, &a).unwrap()[1], f64::INFINITY);
+
+        let red = a.iter().flatten().map(|p| (p[0].saturating_add(10) - p[0]) as f64).map(|d| d * d).sum::<f64>() / (128. * 96.);
+        assert_eq!(mse(&a, &b).unwrap(), [red, 0., 0.]);
+        let psnr = psnr(&a, &b).unwrap();
+        assert!((psnr[0] - 10. * (65025. / red).log10()).abs() < 1e-9);
+        assert_eq!(psnr[1], f64::INFINITY);
+        let luma = psnr_luma(&a, &b).unwrap();
+        assert!(luma > psnr[0] && luma.is_finite(), "{luma}");
+    }
+
+    #[test]
+    fn test_ssim() {
+        let original = pattern();
+        assert!((ssim(&original, &original).unwrap() - 1.).abs() < 1e-9);
+        assert!((ms_ssim(&original, &original).unwrap() - 1.).abs() < 1e-9);
+
+        let high = decode_binary(&encode(&original, &[(2, 2), (1, 1), (1, 1)], 95, 0)).unwrap();
+        let low = decode_binary(&encode(&original, &[(2, 2), (1, 1), (1, 1)], 15, 0)).unwrap();
+        let (high, low) = (quality_metrics(&original, &high).unwrap(), quality_metrics(&original, &low).unwrap());
+        assert!(high.psnr_luma > low.psnr_luma);
+        assert!(1. > high.ssim && high.ssim > low.ssim && low.ssim > 0., "{high:?} {low:?}");
+        assert!(1. > high.ms_ssim && high.ms_ssim > low.ms_ssim && low.ms_ssim > 0., "{high:?} {low:?}");
+
+        // Smaller than the window
+        let small = vec![vec![[10, 20, 30], [40, 50, 60]]; 3];
+        assert!((ssim(&small, &small).unwrap() - 1.).abs() < 1e-9);
+        assert!((ms_ssim(&small, &small).unwrap() - 1.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_diff_heatmap() {
+        let a = vec![vec![[100, 100, 100]; 3]; 2];
+        let mut b = a.clone();
+        b[0][1] = [100, 130, 100];
+        b[1][2] = [90, 100, 100];
+        let heatmap = diff_heatmap(&a, &b).unwrap();
+        assert_eq!(heatmap[0][0], [0, 0, 0]);
+        assert_eq!(heatmap[0][1], [255, 255, 255]);
+        assert_eq!(heatmap[1][2], [255, 0, 0]);
+        assert_eq!(diff_heatmap(&a, &a).unwrap()[1][1], [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_wrong_sizes() {
+        let a = vec![vec![[100, 100, 100]; 3]; 2];
+        let sizes = Error::InvalidArgument("Images of different sizes");
+        assert_eq!(mse(&a, &a[..1]).unwrap_err(), sizes);
+        assert_eq!(ssim(&a, &vec![vec![[0; 3]; 2]; 2]).unwrap_err(), sizes);
+        assert_eq!(psnr(&[], &a).unwrap_err(), Error::InvalidArgument("Empty image"));
+        assert_eq!(quality_metrics(&a, &[]).unwrap_err(), Error::InvalidArgument("Empty image"));
+        let ragged = vec![vec![[0; 3]; 3], vec![[0; 3]; 2]];
+        assert_eq!(diff_heatmap(&a, &ragged).unwrap_err(), Error::InvalidArgument("Rows of different widths"));
+    }
+}
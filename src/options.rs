@@ -0,0 +1,18 @@
+use crate::conceal::Concealment;
+use crate::limits::DecoderLimits;
+use crate::transf::{ColorConversion, Scale, Upsampling};
+
+/// Settings of a full image decode. The defaults give a full size image
+/// with fancy chroma upsampling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DecodeOptions {
+    pub scale: Scale,
+    pub upsampling: Upsampling,
+    pub conversion: ColorConversion,
+    /// Only used by `decode_lenient`.
+    pub concealment: Concealment,
+    pub limits: DecoderLimits,
+    /// Spreads the work over the rayon thread pool, with the `rayon`
+    /// feature only. The image is the same either way.
+    pub parallel: bool,
+}
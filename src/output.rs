@@ -0,0 +1,192 @@
+use crate::error::Error;
+use crate::options::DecodeOptions;
+use crate::stream::RowDecoder;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb8,
+    Rgba8,
+    Bgr8,
+    Bgra8,
+    /// Luma only.
+    L8,
+    /// 16 bits per channel, native endianness.
+    Rgb16,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 => 3,
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4,
+            PixelFormat::L8 => 1,
+            PixelFormat::Rgb16 => 6,
+        }
+    }
+}
+
+fn write_pixel(format: PixelFormat, [r, g, b]: [u8; 3], out: &mut [u8]) {
+    match format {
+        PixelFormat::Rgb8 => out.copy_from_slice(&[r, g, b]),
+        PixelFormat::Rgba8 => out.copy_from_slice(&[r, g, b, 255]),
+        PixelFormat::Bgr8 => out.copy_from_slice(&[b, g, r]),
+        PixelFormat::Bgra8 => out.copy_from_slice(&[b, g, r, 255]),
+        PixelFormat::L8 => unreachable!("L8 is written from the luma samples"),
+        PixelFormat::Rgb16 => {
+            for (chunk, value) in out.chunks_exact_mut(2).zip([r, g, b]) {
+                chunk.copy_from_slice(&(value as u16 * 257).to_ne_bytes());
+            }
+        }
+    }
+}
+
+/// Decoded pixels, `stride` bytes apart from one row to the next.
+#[derive(Debug)]
+pub struct DecodedImage {
+    pub width: u16,
+    pub height: u16,
+    pub format: PixelFormat,
+    pub data: Vec<u8>,
+    pub stride: usize,
+}
+
+impl DecodedImage {
+    /// Bytes of the pixel at (`x`, `y`).
+    pub fn pixel(&self, x: usize, y: usize) -> &[u8] {
+        let bpp = self.format.bytes_per_pixel();
+        &self.data[y * self.stride + x * bpp..][..bpp]
+    }
+}
+
+pub fn decode(data: &[u8], format: PixelFormat) -> Result<DecodedImage, Error> {
+    decode_with(data, format, &DecodeOptions::default())
+}
+
+/// Same as `decode`, with `options.upsampling`, `options.conversion` and
+/// `options.limits`. The other options are not used.
+pub fn decode_with(data: &[u8], format: PixelFormat, options: &DecodeOptions) -> Result<DecodedImage, Error> {
+    let decoder = RowDecoder::with_options(data, options)?;
+    let (width, height) = (decoder.width(), decoder.height());
+    let stride = width as usize * format.bytes_per_pixel();
+
+    let mut pixels = vec![0; stride * height as usize];
+    decode_rows(decoder, &mut pixels, format, stride)?;
+    Ok(DecodedImage {
+        width,
+        height,
+        format,
+        data: pixels,
+        stride,
+    })
+}
+
+/// Decodes straight into `buf`, rows being `stride` bytes apart. Bytes
+/// between the end of a row and the next one are left untouched.
+pub fn decode_into(data: &[u8], buf: &mut [u8], format: PixelFormat, stride: usize) -> Result<(), Error> {
+    decode_into_with(data, buf, format, stride, &DecodeOptions::default())
+}
+
+/// Same as `decode_into`, with `options.upsampling`, `options.conversion`
+/// and `options.limits`. The other options are not used.
+pub fn decode_into_with(data: &[u8], buf: &mut [u8], format: PixelFormat, stride: usize, options: &DecodeOptions) -> Result<(), Error> {
+    decode_rows(RowDecoder::with_options(data, options)?, buf, format, stride)
+}
+
+/// Writes the rows of `decoder` into `buf`, see `decode_into`. L8 comes
+/// straight from the luma samples, without colour conversion.
+fn decode_rows(mut decoder: RowDecoder, buf: &mut [u8], format: PixelFormat, stride: usize) -> Result<(), Error> {
+    let width = decoder.width() as usize;
+    let height = decoder.height() as usize;
+    let bpp = format.bytes_per_pixel();
+
+    let needed = if height == 0 { 0 } else { stride * (height - 1) + width * bpp };
+    if stride < width * bpp || buf.len() < needed {
+        return Err(Error::BufferTooSmall);
+    }
+
+    let mut y = 0;
+    if format == PixelFormat::L8 {
+        for _ in 0..decoder.mcu_rows() {
+            for samples in decoder.read_luma_row().unwrap_or(Err(Error::Truncated))? {
+                buf[y * stride..][..width].copy_from_slice(&samples);
+                y += 1;
+            }
+        }
+        return Ok(());
+    }
+    for _ in 0..decoder.mcu_rows() {
+        for pixels in decoder.read_mcu_row().unwrap_or(Err(Error::Truncated))? {
+            let line = &mut buf[y * stride..][..width * bpp];
+            for (out, &pixel) in line.chunks_exact_mut(bpp).zip(&pixels) {
+                write_pixel(format, pixel, out);
+            }
+            y += 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn test_decode_formats() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let rgb = crate::decode_binary(&data).unwrap();
+
+        let img = decode(&data, PixelFormat::Rgb8).unwrap();
+        assert_eq!((img.width, img.height, img.stride), (34, 16, 102));
+        assert_eq!(img.pixel(20, 7), rgb[7][20]);
+
+        let [r, g, b] = rgb[9][33];
+        assert_eq!(decode(&data, PixelFormat::Bgra8).unwrap().pixel(33, 9), [b, g, r, 255]);
+        assert_eq!(decode(&data, PixelFormat::Rgb16).unwrap().pixel(33, 9)[..2], (r as u16 * 257).to_ne_bytes());
+
+        // L8 is the luma plane, not converted back from RGB
+        let luma = decode(&data, PixelFormat::L8).unwrap();
+        assert_eq!(luma.data.len(), 34 * 16);
+        let planes = crate::decode_planes(&data).unwrap();
+        for y in 0..16 {
+            for (x, &sample) in planes.planes[0].row(y).iter().enumerate() {
+                assert!(luma.pixel(x, y)[0].abs_diff(sample) <= 1, "{x}, {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_into_stride() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let rgb = crate::decode_binary(&data).unwrap();
+
+        let stride = 40 * 4;
+        let mut buf = vec![7; stride * 16];
+        decode_into(&data, &mut buf, PixelFormat::Rgba8, stride).unwrap();
+        assert_eq!(buf[stride + 33 * 4..stride + 34 * 4], [rgb[1][33][0], rgb[1][33][1], rgb[1][33][2], 255]);
+        assert_eq!(buf[stride + 34 * 4], 7);
+
+        let mut small = vec![0; stride * 15];
+        assert_eq!(decode_into(&data, &mut small, PixelFormat::Rgba8, stride), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        assert_eq!(decode(b"\xFF\xD8\xFF\xD9", PixelFormat::Rgb8).unwrap_err(), Error::Format("Missing baseline frame header"));
+
+        // Garbage in place of each header byte in turn
+        let headers = crate::parsing::headers_length(&data).unwrap();
+        for i in 2..headers {
+            let mut corrupt = data.clone();
+            corrupt[i] = !corrupt[i];
+            let _ = decode(&corrupt, PixelFormat::Rgb8);
+        }
+
+        // Data beyond the last MCU
+        let mut longer = data[..data.len() - 2].to_vec();
+        longer.extend_from_within(headers..);
+        assert_eq!(decode(&longer, PixelFormat::Rgb8).unwrap().data, decode(&data, PixelFormat::Rgb8).unwrap().data);
+        assert!(crate::read_coefficients(&longer, crate::CoefficientOrder::Zigzag).is_ok());
+    }
+}
@@ -0,0 +1,156 @@
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::error::Error;
+use crate::parsing::Segments;
+use crate::scan;
+#[cfg(feature = "rayon")]
+use crate::scan::ScanReader;
+
+/// `f` applied to each item, on the rayon thread pool if `parallel` and the
+/// `rayon` feature is enabled.
+#[cfg(feature = "rayon")]
+pub fn map<T: Sync, R: Send>(items: &[T], parallel: bool, f: impl Fn(&T) -> R + Sync + Send) -> Vec<R> {
+    if parallel {
+        items.par_iter().map(f).collect()
+    } else {
+        items.iter().map(f).collect()
+    }
+}
+
+#[cfg(not(feature = "rayon"))]
+pub fn map<T, R>(items: &[T], _parallel: bool, f: impl Fn(&T) -> R) -> Vec<R> {
+    items.iter().map(f).collect()
+}
+
+/// `f` applied to each item and its index, in place, on the rayon thread
+/// pool under the same conditions as `map`.
+#[cfg(feature = "rayon")]
+pub fn for_each<T: Send>(items: &mut [T], parallel: bool, f: impl Fn(usize, &mut T) + Sync + Send) {
+    if parallel {
+        items.par_iter_mut().enumerate().for_each(|(i, item)| f(i, item));
+    } else {
+        items.iter_mut().enumerate().for_each(|(i, item)| f(i, item));
+    }
+}
+
+#[cfg(not(feature = "rayon"))]
+pub fn for_each<T>(items: &mut [T], _parallel: bool, f: impl Fn(usize, &mut T)) {
+    items.iter_mut().enumerate().for_each(|(i, item)| f(i, item));
+}
+
+/// Same as `scan::scan_blocks`. If `parallel`, the scan has restart
+/// intervals and the `rayon` feature is enabled, the intervals are decoded
+/// in parallel, all with the Huffman tables of `segments`.
+pub fn scan_blocks(segments: &Segments, parallel: bool) -> Result<Vec<[i16; 64]>, Error> {
+    let mut blocks = Vec::new();
+    scan_blocks_into(segments, parallel, &mut blocks)?;
+    Ok(blocks)
+}
+
+/// Same as `scan_blocks`, replacing the content of `blocks`.
+#[cfg_attr(not(feature = "rayon"), allow(unused_variables))]
+pub fn scan_blocks_into(segments: &Segments, parallel: bool, blocks: &mut Vec<[i16; 64]>) -> Result<(), Error> {
+    #[cfg(feature = "rayon")]
+    if parallel && segments.restart_interval > 0 {
+        blocks.clear();
+        return scan_intervals(segments, blocks);
+    }
+    scan::scan_blocks_into(segments, blocks)
+}
+
+#[cfg(feature = "rayon")]
+fn scan_intervals(segments: &Segments, blocks: &mut Vec<[i16; 64]>) -> Result<(), Error> {
+    let first = ScanReader::new(segments)?;
+    let layout = first.layout();
+    let total = layout.mcus_wide * layout.mcus_high;
+    let interval = segments.restart_interval as usize;
+
+    // Each interval starts right after a restart marker, the first one at
+    // the start of the scan
+    let scan = segments.start_of_scan.as_ref().unwrap().data;
+    let markers = (0..scan.len().saturating_sub(1)).filter(|&i| scan[i] == 0xFF && (0xD0..=0xD7).contains(&scan[i + 1]));
+    let offsets: Vec<usize> = std::iter::once(0)
+        .chain(markers.map(|i| i + 2))
+        .take(total.div_ceil(interval))
+        .collect();
+
+    // Blocks of each interval, and whether decoding goes on after it: as
+    // the sequential decoder does, an interval cut short or not followed by
+    // a restart marker ends the scan
+    let intervals: Vec<(Vec<[i16; 64]>, bool)> = (0..offsets.len())
+        .into_par_iter()
+        .map(|n| {
+            let mut reader = first.clone();
+            reader.start_interval(n, offsets[n]);
+            let end = usize::min((n + 1) * interval, total);
+
+            let mut blocks = Vec::with_capacity((end - n * interval) * layout.blocks_per_mcu());
+            while reader.position() < end {
+                match reader.next_mcu(&segments.huffman_tables) {
+                    Some(mcu) => blocks.extend(mcu),
+                    None => return (blocks, false),
+                }
+            }
+            (blocks, end < total && reader.read_restart())
+        })
+        .collect();
+
+    for (interval_blocks, more) in intervals {
+        blocks.extend(interval_blocks);
+        if !more {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "rayon"))]
+mod test {
+    use std::fs;
+    use crate::fixtures;
+    use crate::parsing;
+    use crate::{decode_binary_with, decode_lenient, DecodeOptions};
+    use super::*;
+
+    fn fixture() -> Vec<u8> {
+        fixtures::fixture(90, 75, &[(2, 2), (1, 1), (1, 1)], 85, 5)
+    }
+
+    fn blocks(data: &[u8], parallel: bool) -> Vec<[i16; 64]> {
+        scan_blocks(&parsing::parse(data), parallel).unwrap()
+    }
+
+    #[test]
+    fn test_scan_intervals() {
+        let data = fixture();
+        let sequential = blocks(&data, false);
+        assert_eq!(sequential.len(), 6 * 5 * 6);
+        assert_eq!(blocks(&data, true), sequential);
+
+        let markers: Vec<usize> = (0..data.len() - 1)
+            .filter(|&i| data[i] == 0xFF && (0xD0..=0xD7).contains(&data[i + 1]))
+            .collect();
+        // Truncated, missing restart marker, garbage before a marker
+        let truncated = &data[..markers[2] + 20];
+        let missing = [&data[..markers[1]], &data[markers[1] + 2..]].concat();
+        let garbage = [&data[..markers[3]], &[0x12, 0x34], &data[markers[3]..]].concat();
+        for corrupt in [truncated, &missing, &garbage] {
+            let decoded = blocks(corrupt, false);
+            assert!(decoded.len() < sequential.len());
+            assert_eq!(blocks(corrupt, true), decoded);
+        }
+    }
+
+    #[test]
+    fn test_parallel_decode() {
+        let options = DecodeOptions { parallel: true, ..Default::default() };
+        for data in [fixture(), fs::read("img/maps.jpg").expect("Failed to read image")] {
+            assert_eq!(decode_binary_with(&data, &options).unwrap(), decode_binary_with(&data, &DecodeOptions::default()).unwrap());
+        }
+
+        let data = fixture();
+        let truncated = &data[..data.len() / 2];
+        assert_eq!(decode_lenient(truncated, &options), decode_lenient(truncated, &DecodeOptions::default()));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::error::Error;
 use crate::huffman;
 
 #[allow(dead_code)]
@@ -8,6 +9,7 @@ pub struct Segments<'a> {
     pub start_of_frame: Option<StartOfFrame>,
     pub huffman_tables: Vec<HuffmanTable>,
     pub start_of_scan: Option<StartOfScan<'a>>,
+    pub restart_interval: u16,
     pub comments: Vec<String>,
     pub scan: Vec<u8>,
 }
@@ -31,7 +33,12 @@ pub struct Application0<'a> {
 }
 
 impl<'a> Application0<'a> {
-    fn new(bytes: &'a [u8]) -> Application0<'a> {
+    /// Whether `bytes` hold a JFIF header `new` can read.
+    pub fn is_jfif(bytes: &[u8]) -> bool {
+        bytes.len() >= 14 && bytes.starts_with(b"JFIF\0") && bytes[7] <= 2
+    }
+
+    pub fn new(bytes: &'a [u8]) -> Application0<'a> {
         Application0 {
             identifier: String::from_utf8(bytes[0..5].to_vec()).expect("Identifier parsing"),
             version: (bytes[5], bytes[6]),
@@ -55,12 +62,22 @@ impl<'a> Application0<'a> {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Destination {
     Luminance,
     Chrominance,
 }
 
+/// Table destination `id`, `None` for the ones beyond the two of baseline
+/// JPEG.
+fn destination(id: u8) -> Option<Destination> {
+    match id {
+        0 => Some(Destination::Luminance),
+        1 => Some(Destination::Chrominance),
+        _ => None,
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct QuantizationTable {
@@ -69,19 +86,25 @@ pub struct QuantizationTable {
 }
 
 impl QuantizationTable {
-    fn new(bytes: &[u8]) -> QuantizationTable {
-        if bytes.len() < 65 {
-            panic!("Not enough bytes for a quantization table");
-        }
-
-        QuantizationTable {
-            destination: match bytes[0] & 0x0F {
-                0 => Destination::Luminance,
-                1 => Destination::Chrominance,
-                _ => panic!("Wrong destination"),
-            },
-            table: bytes[1..=64].try_into().expect("Slice should be exactly 64 bytes"),
+    /// Tables of a DQT segment, which may hold several. Only the 8-bit
+    /// tables for a baseline destination are kept, and reading stops at a
+    /// truncated one.
+    fn read_all(mut bytes: &[u8]) -> Vec<QuantizationTable> {
+        let mut tables = Vec::new();
+        while let Some(&pq_tq) = bytes.first() {
+            let size = if pq_tq >> 4 == 0 { 64 } else { 128 };
+            let Some(values) = bytes.get(1..=size) else {
+                break;
+            };
+            if let (64, Some(destination)) = (size, destination(pq_tq & 0x0F)) {
+                tables.push(QuantizationTable {
+                    destination,
+                    table: values.try_into().expect("Slice should be exactly 64 bytes"),
+                });
+            }
+            bytes = &bytes[1 + size..];
         }
+        tables
     }
 }
 
@@ -90,6 +113,19 @@ pub enum ComponentId {
     LumY,
     ChromCb,
     ChromCr,
+    Other(u8),
+}
+
+impl ComponentId {
+    /// Identifier as written in the frame header.
+    pub fn number(&self) -> u8 {
+        match self {
+            ComponentId::LumY => 1,
+            ComponentId::ChromCb => 2,
+            ComponentId::ChromCr => 3,
+            ComponentId::Other(id) => *id,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -100,6 +136,17 @@ pub struct ComponentSOF {
     pub quantization_table: u8,
 }
 
+impl ComponentSOF {
+    /// Destination of the quantization table, any table beyond the first
+    /// being read as the chrominance one.
+    pub fn quantization_destination(&self) -> Destination {
+        match self.quantization_table {
+            0 => Destination::Luminance,
+            _ => Destination::Chrominance,
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct StartOfFrame {
@@ -110,7 +157,15 @@ pub struct StartOfFrame {
 }
 
 impl StartOfFrame {
-    fn new(bytes: &[u8]) -> StartOfFrame {
+    /// `None` if `bytes` are too short for the components they announce.
+    pub fn try_new(bytes: &[u8]) -> Option<StartOfFrame> {
+        if bytes.len() < 6 || bytes.len() < 6 + 3 * bytes[5] as usize {
+            return None;
+        }
+        Some(StartOfFrame::new(bytes))
+    }
+
+    pub fn new(bytes: &[u8]) -> StartOfFrame {
         StartOfFrame {
             precision: bytes[0],
             height: u16::from_be_bytes([bytes[1], bytes[2]]),
@@ -121,7 +176,7 @@ impl StartOfFrame {
                         1 => ComponentId::LumY,
                         2 => ComponentId::ChromCb,
                         3 => ComponentId::ChromCr,
-                        _ => panic!("Wrong component id"),
+                        id => ComponentId::Other(id),
                     },
                     factors: (
                         bytes[7 + i * 3] >> 4,
@@ -131,60 +186,63 @@ impl StartOfFrame {
                 }).collect()
         }
     }
-    
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Class {
     AC,
     DC,
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HuffmanTable {
     pub class: Class,
     pub destination: Destination,
-    pub tree: huffman::Tree,
+    pub lookup: huffman::Lookup,
 }
 
 impl HuffmanTable {
-    fn new(bytes: &[u8]) -> HuffmanTable {
-        HuffmanTable {
-            class: match bytes[0] >> 4 {
-                0 => Class::DC,
-                1 => Class::AC,
-                _ => panic!("Wrong class"),
-            },
-            destination: match bytes[0] & 0x0F {
-                0 => Destination::Luminance,
-                1 => Destination::Chrominance,
-                _ => panic!("Wrong class"),
-            },
-            tree: {
-                let mut tree = huffman::Tree::new();
-                let mut pos: usize = 0;
-                let mut code: u16 = 0;
-                for (n, &q) in bytes[1..17].iter().enumerate() {
-                    for _ in 0..q {
-                        let symbol = bytes[17+pos];
-                        tree.insert_code(code, (n+1) as u8, symbol);
-                        pos += 1;
-                        code += 1;
-                    }
-                    code <<= 1;
-                }
-                tree
-            }
+    /// `None` if the table is truncated, has more codes of a length than
+    /// fit or a destination beyond the baseline ones.
+    pub fn new(bytes: &[u8]) -> Option<HuffmanTable> {
+        let class = match bytes.first()? >> 4 {
+            0 => Class::DC,
+            1 => Class::AC,
+            _ => return None,
+        };
+        let counts = bytes.get(1..17)?.try_into().unwrap();
+        let lookup = huffman::Lookup::new(counts, &bytes[17..])?;
+        Some(HuffmanTable {
+            class,
+            destination: destination(bytes[0] & 0x0F)?,
+            lookup,
+        })
+    }
+
+    /// Bytes of each table of a DHT segment, which may hold several: class
+    /// and destination, code counts and symbols. Splitting stops at a
+    /// truncated table.
+    fn split(mut bytes: &[u8]) -> Vec<&[u8]> {
+        let mut tables = Vec::new();
+        while let Some(counts) = bytes.get(1..17) {
+            let size = 17 + counts.iter().map(|&n| n as usize).sum::<usize>();
+            let Some(table) = bytes.get(..size) else {
+                break;
+            };
+            tables.push(table);
+            bytes = &bytes[size..];
         }
-    } 
+        tables
+    }
 }
 
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct ComponentSOS {
-    dc_table: Destination,
-    ac_table: Destination,
+    pub id: u8,
+    pub dc_table: Destination,
+    pub ac_table: Destination,
 }
 
 #[allow(dead_code)]
@@ -195,75 +253,139 @@ pub struct StartOfScan<'a>{
 }
 
 impl<'a> StartOfScan<'a> {
-    fn new(bytes: &[u8]) -> StartOfScan<'a> {
-        StartOfScan {
-            components: {
-                let mut comps = Vec::new();
-                let n_comp = bytes[0] as usize;
-                for i in 1..=n_comp {
-                    comps.push(ComponentSOS {
-                        dc_table: match bytes[i*2] >> 4 {
-                            0 => Destination::Luminance,
-                            1 => Destination::Chrominance,
-                            _ => panic!("Wrong destination"),
-                        },
-                        ac_table: match bytes[i*2] & 0x0F {
-                            0 => Destination::Luminance,
-                            1 => Destination::Chrominance,
-                            _ => panic!("Wrong destination"),
-                        },
-                    });
+    /// `None` if the header is truncated or refers to a table destination
+    /// beyond the baseline ones.
+    fn new(bytes: &[u8]) -> Option<StartOfScan<'a>> {
+        let n_comp = *bytes.first()? as usize;
+        let components = bytes.get(1..1 + 2 * n_comp)?
+            .chunks_exact(2)
+            .map(|c| {
+                Some(ComponentSOS {
+                    id: c[0],
+                    dc_table: destination(c[1] >> 4)?,
+                    ac_table: destination(c[1] & 0x0F)?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(StartOfScan { components, data: &[] })
+    }
+}
+
+pub fn get_lenght(bytes: &[u8]) -> usize {
+    u16::from_be_bytes([bytes[0], bytes[1]]) as usize
+}
+
+/// Offset of the entropy-coded data once every segment up to and including
+/// the start of scan header is available, `None` while `bytes` is too short.
+pub fn headers_length(bytes: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i + 4 <= bytes.len() {
+        match (bytes[i], bytes[i + 1]) {
+            (0xFF, 0xD8) => i += 2,
+            (0xFF, 0xFF) => i += 1,
+            (0xFF, marker) => {
+                let end = i + 2 + get_lenght(&bytes[i+2..=i+3]);
+                if marker == 0xDA && end <= bytes.len() {
+                    return Some(end);
                 }
-                comps
-            },
-            data: &[],
+                i = end;
+            }
+            _ => i += 1,
         }
     }
+    None
 }
 
-fn get_lenght(bytes: &[u8]) -> usize {
-    u16::from_be_bytes([bytes[0], bytes[1]]) as usize
+/// Reads the segments the decoder needs. Malformed segments are skipped and
+/// reading stops at a truncated one: see `validate` for what decoding
+/// requires.
+pub fn parse(bytes: &[u8]) -> Segments<'_> {
+    parse_with(bytes, HuffmanTable::new)
 }
 
-pub fn parse(bytes: &[u8]) -> Segments {
+/// Same as `parse`, each Huffman table being built by `huffman` from its
+/// bytes in a DHT segment.
+pub fn parse_with(bytes: &[u8], mut huffman: impl FnMut(&[u8]) -> Option<HuffmanTable>) -> Segments<'_> {
     let mut segments = Segments::default();
 
     let mut i = 0;
     let mut i_sos = 0;
-    while i < bytes.len() {
-        if bytes[i] == 0xFF {
-            if bytes[i + 1] == 0xE0 {
-                let length = get_lenght(&bytes[i+2..=i+3]);
-                segments.application = Some(Application0::new(&bytes[i+4..i+2+length]));
-                i += length + 1;
-            } else if bytes[i + 1] == 0xDB {
-                let length = get_lenght(&bytes[i+2..=i+3]);
-                segments.quantization_tables.push(QuantizationTable::new(&bytes[i+4..i+2+length]));
-                i += length + 1;
-            } else if bytes[i + 1] == 0xC0 {
-                let length = get_lenght(&bytes[i+2..=i+3]);
-                segments.start_of_frame = Some(StartOfFrame::new(&bytes[i+4..i+2+length]));
-                i += length + 1;
-            } else if bytes[i + 1] == 0xC4 {
-                let length = get_lenght(&bytes[i+2..=i+3]);
-                segments.huffman_tables.push(HuffmanTable::new(&bytes[i+4..i+2+length]));
-                i += length + 1;
-            } else if bytes[i + 1] == 0xDA {
-                let length = get_lenght(&bytes[i+2..=i+3]);
-                segments.start_of_scan = Some(StartOfScan::new(&bytes[i+4..i+2+length]));
-                i += length + 1;
-                i_sos = i + 1;
-            } else if bytes[i + 1] == 0xD9 {
-                segments.start_of_scan.as_mut().unwrap().data = &bytes[i_sos..i];
+    while i + 1 < bytes.len() {
+        if bytes[i] == 0xFF && bytes[i + 1] == 0xD9 {
+            if let Some(sos) = segments.start_of_scan.as_mut() {
+                sos.data = &bytes[i_sos..i];
             }
+        } else if bytes[i] == 0xFF && matches!(bytes[i + 1], 0xE0 | 0xDB | 0xC0 | 0xC4 | 0xDD | 0xDA) {
+            let Some(length) = bytes.get(i + 2..i + 4).map(get_lenght) else {
+                break;
+            };
+            let Some(segment) = bytes.get(i + 4..i + 2 + length) else {
+                break;
+            };
+            match bytes[i + 1] {
+                0xE0 if Application0::is_jfif(segment) => segments.application = Some(Application0::new(segment)),
+                0xDB => segments.quantization_tables.extend(QuantizationTable::read_all(segment)),
+                0xC0 => segments.start_of_frame = StartOfFrame::try_new(segment),
+                0xC4 => segments.huffman_tables.extend(HuffmanTable::split(segment).into_iter().filter_map(&mut huffman)),
+                0xDD => segments.restart_interval = segment.get(0..2).map_or(0, get_lenght) as u16,
+                0xDA => {
+                    segments.start_of_scan = StartOfScan::new(segment);
+                    i_sos = i + 2 + length;
+                }
+                _ => {}
+            }
+            i += length + 1;
         }
         i += 1;
     }
+    // No end of image: the scan data was truncated
+    if let Some(sos) = segments.start_of_scan.as_mut().filter(|sos| sos.data.is_empty()) {
+        sos.data = &bytes[i_sos.min(bytes.len())..];
+    }
     segments
 }
 
+/// Checks that the segments hold a baseline frame the decoder supports and
+/// every table its scan refers to, so that decoding can only stop on the
+/// entropy-coded data.
+pub fn validate(segments: &Segments) -> Result<(), Error> {
+    let sof = segments.start_of_frame.as_ref().ok_or(Error::Format("Missing baseline frame header"))?;
+    if sof.precision != 8 {
+        return Err(Error::Format("Unsupported sample precision"));
+    }
+    if sof.width == 0 || sof.height == 0 {
+        return Err(Error::Format("Empty frame"));
+    }
+    if !(1..=4).contains(&sof.components.len()) {
+        return Err(Error::Format("Unsupported number of components"));
+    }
+    let factors = |c: &ComponentSOF| [c.factors.0, c.factors.1].iter().all(|f| (1..=4).contains(f));
+    if !sof.components.iter().all(factors) {
+        return Err(Error::Format("Wrong sampling factors"));
+    }
+    let blocks: u8 = sof.components.iter().map(|c| c.factors.0 * c.factors.1).sum();
+    if sof.components.len() > 1 && blocks > 10 {
+        return Err(Error::Format("Too many blocks per MCU"));
+    }
+    for component in &sof.components {
+        let destination = component.quantization_destination();
+        if !segments.quantization_tables.iter().any(|t| t.destination == destination) {
+            return Err(Error::Format("Missing quantization table"));
+        }
+    }
 
-
+    let sos = segments.start_of_scan.as_ref().ok_or(Error::Format("Missing scan header"))?;
+    if sos.components.len() != sof.components.len() {
+        return Err(Error::Format("Scans of part of the components not supported"));
+    }
+    let huffman = |class: Class, destination: &Destination| {
+        segments.huffman_tables.iter().any(|t| t.class == class && t.destination == *destination)
+    };
+    if !sos.components.iter().all(|c| huffman(Class::DC, &c.dc_table) && huffman(Class::AC, &c.ac_table)) {
+        return Err(Error::Format("Missing Huffman table"));
+    }
+    Ok(())
+}
 
 #[cfg(test)]
 mod tests {
@@ -342,6 +464,83 @@ mod tests {
         assert_eq!(segments.huffman_tables.len(), 4, "Expected four Huffman tables");
 
     }
-}
 
+    #[test]
+    fn test_parse_malformed() {
+        let data = fs::read("img/white_square.jpg").expect("Failed to read image");
+        assert!(validate(&parse(&data)).is_ok());
+        for len in 0..data.len() {
+            let _ = validate(&parse(&data[..len]));
+        }
+
+        // Three 1-bit codes in the first DHT
+        let dht = data.windows(2).position(|w| w == [0xFF, 0xC4]).unwrap();
+        let mut corrupt = data.clone();
+        corrupt[dht + 5] = 3;
+        let segments = parse(&corrupt);
+        assert_eq!(segments.huffman_tables.len(), 3);
+        assert_eq!(validate(&segments), Err(Error::Format("Missing Huffman table")));
+
+        // Sampling factors of 0
+        let sof = data.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        let mut corrupt = data.clone();
+        corrupt[sof + 11] = 0;
+        assert_eq!(validate(&parse(&corrupt)), Err(Error::Format("Wrong sampling factors")));
+    }
+
+    #[test]
+    fn test_parse_quantization_tables() {
+        let data = fs::read("img/white_square.jpg").expect("Failed to read image");
+        let tables = parse(&data).quantization_tables;
+
+        // Both tables in a single DQT segment, after a 16-bit one
+        let dqt = data.windows(2).position(|w| w == [0xFF, 0xDB]).unwrap();
+        let mut payload = vec![0x12];
+        payload.extend([0; 128]);
+        for table in &tables {
+            payload.push(if table.destination == Destination::Luminance { 0 } else { 1 });
+            payload.extend(table.table);
+        }
+        let mut merged = data[..dqt].to_vec();
+        merged.extend([0xFF, 0xDB]);
+        merged.extend(((payload.len() + 2) as u16).to_be_bytes());
+        merged.extend(&payload);
+        let rest = data[dqt..].windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        merged.extend(&data[dqt + rest..]);
+
+        let segments = parse(&merged);
+        assert_eq!(segments.quantization_tables.len(), 2);
+        for (parsed, table) in segments.quantization_tables.iter().zip(&tables) {
+            assert_eq!((&parsed.destination, parsed.table), (&table.destination, table.table));
+        }
+        assert!(validate(&segments).is_ok());
+    }
 
+    #[test]
+    fn test_parse_huffman_tables() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+
+        // The four DHT segments merged into one
+        let mut merged = Vec::new();
+        let mut payload = Vec::new();
+        let mut i = 2;
+        while data[i + 1] != 0xDA {
+            let end = i + 2 + get_lenght(&data[i + 2..i + 4]);
+            if data[i + 1] == 0xC4 {
+                payload.extend(&data[i + 4..end]);
+            } else {
+                merged.extend(&data[i..end]);
+            }
+            i = end;
+        }
+        merged.extend([0xFF, 0xC4]);
+        merged.extend(((payload.len() + 2) as u16).to_be_bytes());
+        merged.extend(&payload);
+        let merged = [&data[..2], &merged, &data[i..]].concat();
+
+        let segments = parse(&merged);
+        assert_eq!(segments.huffman_tables.len(), 4);
+        assert!(validate(&segments).is_ok());
+        assert_eq!(crate::decode_binary(&merged).unwrap(), crate::decode_binary(&data).unwrap());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::coefficients::{read_coefficients_with, CoefficientOrder};
+use crate::error::Error;
+use crate::limits::DecoderLimits;
+use crate::transf::{dequantize, idct};
+
+/// One component at its native resolution. Rows are `stride` bytes apart;
+/// the stride covers the whole padded block grid.
+#[derive(Debug)]
+pub struct Plane {
+    pub id: u8,
+    pub factors: (u8, u8),
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub data: Vec<u8>,
+}
+
+impl Plane {
+    pub fn row(&self, y: usize) -> &[u8] {
+        &self.data[y * self.stride..][..self.width]
+    }
+}
+
+/// Components of the image, without upsampling nor colour conversion: for
+/// a YCbCr 4:2:0 image, the planes are those of I420.
+#[derive(Debug)]
+pub struct PlanarImage {
+    pub width: u16,
+    pub height: u16,
+    pub planes: Vec<Plane>,
+}
+
+pub fn decode_planes(data: &[u8]) -> Result<PlanarImage, Error> {
+    decode_planes_with(data, &DecoderLimits::default())
+}
+
+/// Same as `decode_planes`, within `limits`.
+pub fn decode_planes_with(data: &[u8], limits: &DecoderLimits) -> Result<PlanarImage, Error> {
+    let coefficients = read_coefficients_with(data, CoefficientOrder::Zigzag, limits)?;
+    let h_max = coefficients.components.iter().map(|c| c.factors.0).max().unwrap_or(1) as usize;
+    let v_max = coefficients.components.iter().map(|c| c.factors.1).max().unwrap_or(1) as usize;
+
+    let planes = coefficients.components.iter().map(|component| {
+        let (h, v) = (component.factors.0 as usize, component.factors.1 as usize);
+        let stride = component.blocks_wide * 8;
+        let mut pixels = vec![0; stride * component.blocks_high * 8];
+
+        for (k, block) in component.blocks.iter().enumerate() {
+            let (bx, by) = (k % component.blocks_wide, k / component.blocks_wide);
+            let mat = idct(dequantize(block, &component.quantization_table));
+            for (y, row) in mat.iter().enumerate() {
+                let start = (by * 8 + y) * stride + bx * 8;
+                for (out, value) in pixels[start..start + 8].iter_mut().zip(row) {
+                    *out = value.round().clamp(0., 255.) as u8;
+                }
+            }
+        }
+
+        Plane {
+            id: component.id,
+            factors: component.factors,
+            width: usize::div_ceil(coefficients.width as usize * h, h_max),
+            height: usize::div_ceil(coefficients.height as usize * v, v_max),
+            stride,
+            data: pixels,
+        }
+    }).collect();
+
+    Ok(PlanarImage {
+        width: coefficients.width,
+        height: coefficients.height,
+        planes,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use crate::transf::ycbcr_to_rgb;
+    use super::*;
+
+    #[test]
+    fn test_decode_planes() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let img = decode_planes(&data).unwrap();
+
+        let sizes: Vec<_> = img.planes.iter().map(|p| (p.width, p.height, p.stride)).collect();
+        assert_eq!(sizes, [(34, 16, 48), (17, 8, 24), (17, 8, 24)]);
+        assert_eq!(img.planes[1].row(7).len(), 17);
+
+        // Samples are clamped in the planes, so saturated pixels drift from
+        // the RGB path and are skipped
+        let options = crate::DecodeOptions { upsampling: crate::Upsampling::Nearest, ..Default::default() };
+        let rgb = crate::decode_binary_with(&data, &options).unwrap();
+        let mut checked = 0;
+        for (y, row) in rgb.iter().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                let lum = img.planes[0].row(y)[x];
+                let cb = img.planes[1].row(y / 2)[x / 2];
+                let cr = img.planes[2].row(y / 2)[x / 2];
+                if [lum, cb, cr].iter().chain(pixel).any(|&v| v == 0 || v == 255) {
+                    continue;
+                }
+                let converted = ycbcr_to_rgb(lum as f32, cb as f32, cr as f32);
+                for c in 0..3 {
+                    assert!(converted[c].abs_diff(pixel[c]) <= 3, "x: {x}, y: {y}");
+                }
+                checked += 1;
+            }
+        }
+        assert!(checked > 50);
+    }
+
+    #[test]
+    fn test_decode_planes_truncated() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        assert_eq!(decode_planes(&data[..800]).unwrap_err(), Error::Truncated);
+    }
+}
@@ -0,0 +1,323 @@
+//! Estimation of the IJG quality setting an image was encoded with, from its
+//! quantization tables, and of the encoder from its tables and metadata.
+
+use crate::error::Error;
+use crate::parsing::{self, get_lenght, Destination, QuantizationTable};
+use crate::transf::ZIGZAG;
+
+/// Luminance quantization table of Annex K, in natural order.
+pub const LUMINANCE_QUANTIZATION: [[u16; 8]; 8] = [
+    [16, 11, 10, 16, 24, 40, 51, 61],
+    [12, 12, 14, 19, 26, 58, 60, 55],
+    [14, 13, 16, 24, 40, 57, 69, 56],
+    [14, 17, 22, 29, 51, 87, 80, 62],
+    [18, 22, 37, 56, 68, 109, 103, 77],
+    [24, 35, 55, 64, 81, 104, 113, 92],
+    [49, 64, 78, 87, 103, 121, 120, 101],
+    [72, 92, 95, 98, 112, 100, 103, 99],
+];
+
+/// Chrominance quantization table of Annex K, in natural order.
+pub const CHROMINANCE_QUANTIZATION: [[u16; 8]; 8] = [
+    [17, 18, 24, 47, 99, 99, 99, 99],
+    [18, 21, 26, 66, 99, 99, 99, 99],
+    [24, 26, 56, 99, 99, 99, 99, 99],
+    [47, 66, 99, 99, 99, 99, 99, 99],
+    [99, 99, 99, 99, 99, 99, 99, 99],
+    [99, 99, 99, 99, 99, 99, 99, 99],
+    [99, 99, 99, 99, 99, 99, 99, 99],
+    [99, 99, 99, 99, 99, 99, 99, 99],
+];
+
+/// Annex K table scaled like libjpeg's `jpeg_quality_scaling`, in zigzag
+/// order.
+pub fn scaled_table(base: &[[u16; 8]; 8], quality: u8) -> [u8; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 { 5000 / quality } else { 200 - 2 * quality };
+
+    let mut table = [0; 64];
+    for (v, row) in ZIGZAG.iter().enumerate() {
+        for (u, &i) in row.iter().enumerate() {
+            table[i] = ((base[v][u] as u32 * scale + 50) / 100).clamp(1, 255) as u8;
+        }
+    }
+    table
+}
+
+/// Likely encoder of an image. Only libjpeg is recognized by its tables,
+/// which many encoders built on it share, whatever the metadata. The other
+/// encoders are only told by their metadata: Photoshop by the JPEG quality
+/// resource of its APP13 segment, then cameras and software by their Exif
+/// metadata or comments. Their own tables are not matched, so an image
+/// stripped of its metadata is not attributed to them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Encoder {
+    Photoshop,
+    /// libjpeg, libjpeg-turbo or another encoder writing the Annex K tables
+    /// scaled by the IJG quality.
+    Libjpeg,
+    /// Digital camera, from the Exif make and model.
+    Camera { make: String, model: Option<String> },
+    /// Software named by the Exif metadata or a comment.
+    Software(String),
+}
+
+/// Quality of an image as an IJG (libjpeg, `cjpeg -quality`) setting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityEstimate {
+    /// Quality from 1 to 100 whose scaled Annex K tables are the closest to
+    /// the tables of the image.
+    pub quality: u8,
+    /// Whether the tables are exactly the scaled Annex K ones.
+    pub standard: bool,
+    /// Mean absolute difference between the tables of the image and the
+    /// scaled ones, 0 if `standard`.
+    pub mean_error: f64,
+    pub encoder: Option<Encoder>,
+}
+
+fn base_table(destination: &Destination) -> &'static [[u16; 8]; 8] {
+    match destination {
+        Destination::Luminance => &LUMINANCE_QUANTIZATION,
+        Destination::Chrominance => &CHROMINANCE_QUANTIZATION,
+    }
+}
+
+/// Distance between `tables` and the Annex K ones scaled at `quality`:
+/// the sum of the log ratios of their values, so that each value weighs the
+/// same whatever its magnitude, and the mean absolute difference.
+fn distance(tables: &[QuantizationTable], quality: u8) -> (f64, f64) {
+    let (mut log_ratios, mut diffs) = (0., 0.);
+    for table in tables {
+        let scaled = scaled_table(base_table(&table.destination), quality);
+        for (&value, &expected) in table.table.iter().zip(&scaled) {
+            log_ratios += (value.max(1) as f64 / expected as f64).ln().abs();
+            diffs += value.abs_diff(expected) as f64;
+        }
+    }
+    (log_ratios, diffs / (64 * tables.len()) as f64)
+}
+
+/// Make, model and software ASCII tags of the first IFD of an Exif APP1
+/// segment.
+fn exif_tags(bytes: &[u8]) -> Option<[Option<String>; 3]> {
+    let tiff = bytes.strip_prefix(b"Exif\0\0")?;
+    let big_endian = match tiff.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |i: usize| {
+        let b: [u8; 2] = tiff.get(i..i + 2)?.try_into().ok()?;
+        Some(if big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    };
+    let u32_at = |i: usize| {
+        let b: [u8; 4] = tiff.get(i..i + 4)?.try_into().ok()?;
+        Some(if big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) } as usize)
+    };
+
+    let ifd = u32_at(4)?;
+    let mut tags = [None, None, None];
+    for n in 0..u16_at(ifd)? as usize {
+        let entry = ifd + 2 + 12 * n;
+        let slot = match u16_at(entry)? {
+            0x010F => 0,
+            0x0110 => 1,
+            0x0131 => 2,
+            _ => continue,
+        };
+        // ASCII strings, stored in the entry itself when short enough
+        let count = u32_at(entry + 4)?;
+        if u16_at(entry + 2)? != 2 || count == 0 {
+            continue;
+        }
+        let offset = if count <= 4 { entry + 8 } else { u32_at(entry + 8)? };
+        let text = String::from_utf8_lossy(tiff.get(offset..offset + count)?);
+        let text = text.trim_end_matches('\0').trim();
+        if !text.is_empty() {
+            tags[slot] = Some(text.to_string());
+        }
+    }
+    Some(tags)
+}
+
+/// Whether the image resources of a "Photoshop 3.0" APP13 segment hold the
+/// JPEG quality one (0x0406), which Photoshop writes along with the image.
+/// Other software writes the segment as well, for its IPTC resource only.
+fn photoshop_quality(bytes: &[u8]) -> bool {
+    let Some(mut resources) = bytes.strip_prefix(b"Photoshop 3.0\0") else {
+        return false;
+    };
+    while let Some(resource) = resources.strip_prefix(b"8BIM") {
+        let Some(&[id_1, id_2, name_length]) = resource.get(0..3) else {
+            break;
+        };
+        if u16::from_be_bytes([id_1, id_2]) == 0x0406 {
+            return true;
+        }
+        // Pascal string name and data, both padded to an even length
+        let name = (1 + name_length as usize).next_multiple_of(2);
+        let Some(&[s1, s2, s3, s4]) = resource.get(2 + name..6 + name) else {
+            break;
+        };
+        let size = u32::from_be_bytes([s1, s2, s3, s4]) as usize;
+        let Some(next) = resource.get(6 + name + size.next_multiple_of(2)..) else {
+            break;
+        };
+        resources = next;
+    }
+    false
+}
+
+/// Encoder guessed from the segments before the first scan, see `Encoder`.
+fn encoder(data: &[u8], standard: bool) -> Option<Encoder> {
+    let (mut photoshop, mut exif, mut comment) = (false, None, None);
+    let mut i = 2;
+    while let Some(&[0xFF, marker, l1, l2]) = data.get(i..i + 4) {
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let length = get_lenght(&[l1, l2]);
+        let Some(bytes) = data.get(i + 4..i + 2 + length) else {
+            break;
+        };
+        match marker {
+            0xED if photoshop_quality(bytes) => photoshop = true,
+            0xE1 if exif.is_none() => exif = exif_tags(bytes),
+            0xFE if comment.is_none() => {
+                let text = String::from_utf8_lossy(bytes).trim_end_matches('\0').trim().to_string();
+                comment = Some(text).filter(|text| !text.is_empty());
+            }
+            _ => {}
+        }
+        i += 2 + length;
+    }
+
+    let [make, model, software] = exif.unwrap_or_default();
+    if standard {
+        Some(Encoder::Libjpeg)
+    } else if photoshop {
+        Some(Encoder::Photoshop)
+    } else if let Some(make) = make {
+        Some(Encoder::Camera { make, model })
+    } else {
+        software.or(comment).map(Encoder::Software)
+    }
+}
+
+/// Estimates the IJG quality of `data` by comparing its quantization
+/// tables with the Annex K ones scaled at every quality, and guesses its
+/// encoder. Tables of other encoders are only approximated by the closest
+/// quality.
+pub fn estimate_quality(data: &[u8]) -> Result<QualityEstimate, Error> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(Error::Format("Missing start of image"));
+    }
+    let tables = parsing::parse(data).quantization_tables;
+    if tables.is_empty() {
+        return Err(Error::Format("Missing quantization table"));
+    }
+
+    // The first of equally close qualities, when the scaled values are
+    // clamped
+    let mut best = (f64::INFINITY, 0., 0);
+    for quality in 1..=100 {
+        let (log_ratios, mean_error) = distance(&tables, quality);
+        if log_ratios < best.0 {
+            best = (log_ratios, mean_error, quality);
+        }
+    }
+    let (_, mean_error, quality) = best;
+    let standard = mean_error == 0.;
+    Ok(QualityEstimate { quality, standard, mean_error, encoder: encoder(data, standard) })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use crate::fixtures::fixture;
+    use super::*;
+
+    fn image(quality: u8) -> Vec<u8> {
+        fixture(16, 16, &[(2, 2), (1, 1), (1, 1)], quality, 0)
+    }
+
+    /// `data` with a segment inserted after SOI.
+    fn with_segment(data: &[u8], marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut res = data[..2].to_vec();
+        res.extend([0xFF, marker]);
+        res.extend(((payload.len() + 2) as u16).to_be_bytes());
+        res.extend(payload);
+        res.extend(&data[2..]);
+        res
+    }
+
+    /// `data` with its tables multiplied by 1.15, rounded.
+    fn custom(data: &[u8]) -> Vec<u8> {
+        let mut res = data.to_vec();
+        let mut i = 2;
+        while res[i + 1] != 0xDA {
+            let length = get_lenght(&res[i + 2..i + 4]);
+            if res[i + 1] == 0xDB {
+                for value in &mut res[i + 5..i + 69] {
+                    *value = (*value as f32 * 1.15).round().min(255.) as u8;
+                }
+            }
+            i += 2 + length;
+        }
+        res
+    }
+
+    #[test]
+    fn test_estimate_quality() {
+        for quality in [5, 30, 50, 75, 90, 100] {
+            let estimate = estimate_quality(&image(quality)).unwrap();
+            assert_eq!((estimate.quality, estimate.standard), (quality, true));
+            assert_eq!(estimate.encoder, Some(Encoder::Libjpeg));
+        }
+
+        // Coarser tables than at quality 80, the closest match being lower
+        let estimate = estimate_quality(&custom(&image(80))).unwrap();
+        assert!(!estimate.standard && estimate.mean_error > 0.);
+        assert!((74..80).contains(&estimate.quality), "{estimate:?}");
+        assert_eq!(estimate.encoder, None);
+
+        let data = fs::read("img/white_square.jpg").expect("Failed to read image");
+        assert!(estimate_quality(&data).unwrap().quality > 85);
+        assert_eq!(estimate_quality(&data[..20]), Err(Error::Format("Missing quantization table")));
+        assert!(estimate_quality(b"not a JPEG").is_err());
+    }
+
+    #[test]
+    fn test_encoder_signatures() {
+        let data = custom(&image(85));
+        // IPTC resource, then JPEG quality resource with a name
+        let iptc = b"Photoshop 3.0\08BIM\x04\x04\0\0\0\0\0\x03abc\0";
+        let photoshop = [&iptc[..], b"8BIM\x04\x06\x02ab\0\0\0\x04\0\x08\0\x01"].concat();
+        assert_eq!(estimate_quality(&with_segment(&data, 0xED, &photoshop)).unwrap().encoder, Some(Encoder::Photoshop));
+        // Written by any IPTC editor
+        assert_eq!(estimate_quality(&with_segment(&data, 0xED, iptc)).unwrap().encoder, None);
+        let standard = with_segment(&image(85), 0xED, &photoshop);
+        assert_eq!(estimate_quality(&standard).unwrap().encoder, Some(Encoder::Libjpeg));
+
+        // Little endian IFD: make inline, model at an offset, then software
+        let mut tiff = b"II*\0\x08\0\0\0\x03\0".to_vec();
+        tiff.extend([0x0F, 0x01, 2, 0, 4, 0, 0, 0]);
+        tiff.extend(b"Foo\0");
+        tiff.extend([0x10, 0x01, 2, 0, 7, 0, 0, 0, 50, 0, 0, 0]);
+        tiff.extend([0x31, 0x01, 2, 0, 4, 0, 0, 0]);
+        tiff.extend(b"1.0\0");
+        tiff.extend([0; 4]);
+        tiff.extend(b"Cam 12\0");
+        let camera = with_segment(&data, 0xE1, &[&b"Exif\0\0"[..], &tiff].concat());
+        let expected = Encoder::Camera { make: "Foo".into(), model: Some("Cam 12".into()) };
+        assert_eq!(estimate_quality(&camera).unwrap().encoder, Some(expected));
+
+        // Standard tables are libjpeg's, whatever the metadata
+        let camera = with_segment(&image(85), 0xE1, &[&b"Exif\0\0"[..], &tiff].concat());
+        assert_eq!(estimate_quality(&camera).unwrap().encoder, Some(Encoder::Libjpeg));
+
+        let commented = with_segment(&data, 0xFE, b"Some Editor 2.1");
+        assert_eq!(estimate_quality(&commented).unwrap().encoder, Some(Encoder::Software("Some Editor 2.1".into())));
+    }
+}
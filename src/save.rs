@@ -0,0 +1,225 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::Path;
+
+use image::codecs::bmp::BmpEncoder;
+
+use crate::info::{icc_profile, probe};
+use crate::parsing::DensityUnit;
+use crate::transf::rgb_to_luma;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    /// Binary RGB portable pixmap (P6).
+    Ppm,
+    /// Binary grayscale portable graymap (P5).
+    Pgm,
+    Bmp,
+    /// Interleaved RGB bytes, without any header.
+    Raw,
+}
+
+impl OutputFormat {
+    /// Format matching the extension of `path`, if known.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<OutputFormat> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "png" => Some(OutputFormat::Png),
+            "ppm" => Some(OutputFormat::Ppm),
+            "pgm" => Some(OutputFormat::Pgm),
+            "bmp" => Some(OutputFormat::Bmp),
+            "raw" | "rgb" => Some(OutputFormat::Raw),
+            _ => None,
+        }
+    }
+
+    /// Usual extension of the format, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Ppm => "ppm",
+            OutputFormat::Pgm => "pgm",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Raw => "raw",
+        }
+    }
+}
+
+/// Metadata of the source JPEG carried into the saved file, when the
+/// output format supports it.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub icc_profile: Option<Vec<u8>>,
+    pub density: Option<(DensityUnit, (u16, u16))>,
+}
+
+impl Metadata {
+    pub fn read(data: &[u8]) -> Metadata {
+        Metadata {
+            icc_profile: icc_profile(data),
+            density: probe(data).ok().and_then(|info| info.density),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    Encoding(String),
+    UnknownFormat,
+    EmptyImage,
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(err) => write!(f, "I/O error: {err}"),
+            SaveError::Encoding(msg) => write!(f, "Encoding error: {msg}"),
+            SaveError::UnknownFormat => write!(f, "Unknown output format"),
+            SaveError::EmptyImage => write!(f, "Empty image"),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<io::Error> for SaveError {
+    fn from(err: io::Error) -> SaveError {
+        SaveError::Io(err)
+    }
+}
+
+fn pixel_dims(density: &Option<(DensityUnit, (u16, u16))>) -> Option<png::PixelDimensions> {
+    let (unit, (x, y)) = density.as_ref()?;
+    let (xppu, yppu, unit) = match unit {
+        DensityUnit::NoUnit => (*x as u32, *y as u32, png::Unit::Unspecified),
+        DensityUnit::PixelsPerInch => (
+            (*x as f64 / 0.0254).round() as u32,
+            (*y as f64 / 0.0254).round() as u32,
+            png::Unit::Meter,
+        ),
+        DensityUnit::PixelsPerCm => (*x as u32 * 100, *y as u32 * 100, png::Unit::Meter),
+    };
+    Some(png::PixelDimensions { xppu, yppu, unit })
+}
+
+fn write_png<W: Write>(pic: &[Vec<[u8; 3]>], writer: W, metadata: &Metadata) -> Result<(), SaveError> {
+    let mut info = png::Info::with_size(pic[0].len() as u32, pic.len() as u32);
+    info.color_type = png::ColorType::Rgb;
+    info.bit_depth = png::BitDepth::Eight;
+    info.pixel_dims = pixel_dims(&metadata.density);
+    info.icc_profile = metadata.icc_profile.as_deref().map(Cow::Borrowed);
+
+    let encoding = |err: png::EncodingError| SaveError::Encoding(err.to_string());
+    let mut png_writer = png::Encoder::with_info(writer, info).map_err(encoding)?.write_header().map_err(encoding)?;
+    png_writer.write_image_data(&pic.concat().concat()).map_err(encoding)
+}
+
+/// Writes `pic` to `writer` in the given format.
+pub fn write_image<W: Write + Seek>(pic: &[Vec<[u8; 3]>], mut writer: W, format: OutputFormat, metadata: &Metadata) -> Result<(), SaveError> {
+    if pic.is_empty() || pic[0].is_empty() {
+        return Err(SaveError::EmptyImage);
+    }
+    let (width, height) = (pic[0].len(), pic.len());
+
+    match format {
+        OutputFormat::Png => write_png(pic, &mut writer, metadata)?,
+        OutputFormat::Ppm => {
+            write!(writer, "P6\n{width} {height}\n255\n")?;
+            for row in pic {
+                writer.write_all(row.concat().as_slice())?;
+            }
+        }
+        OutputFormat::Pgm => {
+            write!(writer, "P5\n{width} {height}\n255\n")?;
+            for row in pic {
+                writer.write_all(&row.iter().map(|&rgb| rgb_to_luma(rgb)).collect::<Vec<u8>>())?;
+            }
+        }
+        OutputFormat::Bmp => {
+            BmpEncoder::new(&mut writer)
+                .encode(&pic.concat().concat(), width as u32, height as u32, image::ColorType::Rgb8)
+                .map_err(|err| SaveError::Encoding(err.to_string()))?;
+        }
+        OutputFormat::Raw => {
+            for row in pic {
+                writer.write_all(row.concat().as_slice())?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Saves `pic` to `path`, in the format given by its extension.
+pub fn save<P: AsRef<Path>>(pic: &[Vec<[u8; 3]>], path: P, metadata: &Metadata) -> Result<(), SaveError> {
+    let format = OutputFormat::from_path(&path).ok_or(SaveError::UnknownFormat)?;
+    let file = BufWriter::new(File::create(path)?);
+    write_image(pic, file, format, metadata)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use super::*;
+
+    fn pic() -> Vec<Vec<[u8; 3]>> {
+        vec![vec![[255, 0, 0], [0, 255, 0], [0, 0, 255]]; 2]
+    }
+
+    #[test]
+    fn test_output_format_from_path() {
+        assert_eq!(OutputFormat::from_path("out/a.PNG"), Some(OutputFormat::Png));
+        assert_eq!(OutputFormat::from_path("a.pgm"), Some(OutputFormat::Pgm));
+        assert_eq!(OutputFormat::from_path("a.jpg"), None);
+        assert_eq!(OutputFormat::from_path("a"), None);
+
+        for format in [OutputFormat::Png, OutputFormat::Ppm, OutputFormat::Pgm, OutputFormat::Bmp, OutputFormat::Raw] {
+            assert_eq!(OutputFormat::from_path(format!("a.{}", format.extension())), Some(format));
+        }
+    }
+
+    #[test]
+    fn test_write_ppm_and_raw() {
+        let mut buf = Cursor::new(Vec::new());
+        write_image(&pic(), &mut buf, OutputFormat::Ppm, &Metadata::default()).unwrap();
+        let buf = buf.into_inner();
+        assert!(buf.starts_with(b"P6\n3 2\n255\n"));
+        assert_eq!(buf.len(), 11 + 18);
+
+        let mut buf = Cursor::new(Vec::new());
+        write_image(&pic(), &mut buf, OutputFormat::Raw, &Metadata::default()).unwrap();
+        assert_eq!(buf.into_inner()[..6], [255, 0, 0, 0, 255, 0]);
+
+        let mut buf = Cursor::new(Vec::new());
+        assert!(matches!(write_image(&[], &mut buf, OutputFormat::Raw, &Metadata::default()), Err(SaveError::EmptyImage)));
+    }
+
+    #[test]
+    fn test_write_png_metadata() {
+        let metadata = Metadata {
+            icc_profile: Some(b"fake profile".to_vec()),
+            density: Some((DensityUnit::PixelsPerInch, (300, 300))),
+        };
+        let mut buf = Cursor::new(Vec::new());
+        write_image(&pic(), &mut buf, OutputFormat::Png, &metadata).unwrap();
+
+        let decoder = png::Decoder::new(Cursor::new(buf.into_inner()));
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!(info.pixel_dims.unwrap().xppu, 11811);
+        assert_eq!(info.icc_profile.as_deref(), Some(&b"fake profile"[..]));
+    }
+
+    #[test]
+    fn test_write_bmp() {
+        let mut buf = Cursor::new(Vec::new());
+        write_image(&pic(), &mut buf, OutputFormat::Bmp, &Metadata::default()).unwrap();
+        let img = image::load_from_memory(&buf.into_inner()).unwrap().to_rgb8();
+        assert_eq!(img.get_pixel(2, 1).0, [0, 0, 255]);
+    }
+}
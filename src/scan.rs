@@ -1,94 +1,398 @@
+use crate::error::Error;
 use crate::huffman;
-use crate::parsing::{Class, Segments, ComponentId};
+use crate::parsing::{Class, Destination, HuffmanTable, Segments, StartOfFrame};
+use crate::transf::Scale;
 
-#[derive(Default, Debug)]
-struct PrevDC {
-    lum: i16,
-    cb: i16,
-    cr: i16,
+/// Geometry of the MCUs of a frame whose components are all interleaved in
+/// its scan.
+#[derive(Debug, Clone)]
+pub struct McuLayout {
+    pub width: u16,
+    pub height: u16,
+    /// Sampling factors of each component. A single component scan is not
+    /// interleaved: its MCU is one block whatever the factors of the frame.
+    pub factors: Vec<(usize, usize)>,
+    pub h_max: usize,
+    pub v_max: usize,
+    pub mcus_wide: usize,
+    pub mcus_high: usize,
 }
 
-pub fn scan_blocks(segments: &mut Segments) -> Vec<[i16; 64]> {
-    let mut blocks: Vec<[i16; 64]> = Vec::new();
-    let mut block:[i16; 64] = [0; 64];
-
-    let bytes = segments.start_of_scan.as_ref().unwrap().data;
-
-    let mut bit_stream = huffman::BitStream::new(bytes);
-    let mut table = &mut segments.huffman_tables[0]; // DC, Lum
-
-    let mut curr_component = ComponentId::LumY;
-    let mut n_data_units = 4;
-    let mut i = 0;
-    let mut prev_dc = PrevDC::default();
-
-    while let Some(bit) = bit_stream.next_bit() {
-        // print!("{bit:01b}");
-
-        if let Ok(Some(category)) = table.tree.forward(bit) {
-            // println!(" ");
-            // println!("i: {}, c: {:x}, {:?}, {:?}, pos: {}", i, category, &curr_component, &table.class, bit_stream.get_pos());
-            
-            if table.class == Class::DC {
-                match curr_component {
-                    ComponentId::LumY => {
-                        table = &mut segments.huffman_tables[1]; // AC, Lum
-
-                        block[0] = bit_stream.get_coeff(category) + prev_dc.lum;
-                        prev_dc.lum = block[0];
-                    },
-                    ComponentId::ChromCb => {
-                        table = &mut segments.huffman_tables[3]; // AC, Chr
-
-                        block[0] = bit_stream.get_coeff(category) + prev_dc.cb;
-                        prev_dc.cb = block[0];
-                    }
-                    ComponentId::ChromCr => {
-                        table = &mut segments.huffman_tables[3]; // AC, Chr
-
-                        block[0] = bit_stream.get_coeff(category) + prev_dc.cr;
-                        prev_dc.cr = block[0];
-                    }
-                }
-                i += 1;
-            } else {       // Class::AC
-                if i <= 63 && category != 0 {
-                    i += (category as usize) >> 4;
-                    block[i] = bit_stream.get_coeff(category & 0x0F);
-                }
-                if i >= 63 || category == 0 {
-                    match curr_component {
-                        ComponentId::LumY => {
-                            if n_data_units > 1 {
-                                // println!("new DC Lum");
-                                table = &mut segments.huffman_tables[0]; // DC, Lum
-                                n_data_units -= 1;
-                            } else {
-                                table = &mut segments.huffman_tables[2]; // DC, Chr
-                                curr_component = ComponentId::ChromCb;
-                                n_data_units = 4;
-                            }
-                        },
-                        ComponentId::ChromCb => {
-                            table = &mut segments.huffman_tables[2]; // DC, Chr
-                            curr_component = ComponentId::ChromCr;
-                        }
-                        ComponentId::ChromCr => {
-                            table = &mut segments.huffman_tables[0]; // DC, Lum
-                            curr_component = ComponentId::LumY;
-                        }
-                    }
-                    blocks.push(block);
-                    block = [0; 64];
-                    i = 0;
-                } else {
-                    i += 1;
-                }             
+impl McuLayout {
+    pub fn new(sof: &StartOfFrame) -> McuLayout {
+        let factors: Vec<(usize, usize)> = if sof.components.len() == 1 {
+            vec![(1, 1)]
+        } else {
+            sof.components.iter().map(|c| (c.factors.0 as usize, c.factors.1 as usize)).collect()
+        };
+        // At least 1 even for the zero factors `parsing::validate` rejects,
+        // for the limits to be checked beforehand
+        let h_max = factors.iter().map(|f| f.0).max().unwrap_or(1).max(1);
+        let v_max = factors.iter().map(|f| f.1).max().unwrap_or(1).max(1);
+        McuLayout {
+            width: sof.width,
+            height: sof.height,
+            mcus_wide: usize::div_ceil(sof.width as usize, 8 * h_max),
+            mcus_high: usize::div_ceil(sof.height as usize, 8 * v_max),
+            factors,
+            h_max,
+            v_max,
+        }
+    }
+
+    pub fn blocks_per_mcu(&self) -> usize {
+        self.factors.iter().map(|(h, v)| h * v).sum()
+    }
+
+    /// Component of each block of an MCU, in scan order.
+    pub fn block_components(&self) -> Vec<usize> {
+        self.factors
+            .iter()
+            .enumerate()
+            .flat_map(|(c, (h, v))| std::iter::repeat_n(c, h * v))
+            .collect()
+    }
+
+    /// Index of the first block of each component in an MCU.
+    pub fn first_blocks(&self) -> Vec<usize> {
+        self.factors
+            .iter()
+            .scan(0, |first, (h, v)| {
+                let res = *first;
+                *first += h * v;
+                Some(res)
+            })
+            .collect()
+    }
+
+    /// Width and height of an MCU, in pixels of the scaled image.
+    pub fn mcu_size(&self, scale: Scale) -> (usize, usize) {
+        let n = scale.block_size();
+        (n * self.h_max, n * self.v_max)
+    }
+
+    /// Number of samples of component `c`, horizontally and vertically,
+    /// covering the scaled image. The remaining ones only pad the last MCUs.
+    pub fn component_size(&self, c: usize, scale: Scale) -> (usize, usize) {
+        let (h, v) = self.factors[c];
+        let width = scale.scaled(self.width) as usize;
+        let height = scale.scaled(self.height) as usize;
+        ((width * h).div_ceil(self.h_max), (height * v).div_ceil(self.v_max))
+    }
+}
+
+/// Position in the scan data right after the `n`-th restart marker.
+fn restart_offset(data: &[u8], n: usize) -> Option<usize> {
+    if n == 0 {
+        return Some(0);
+    }
+    let mut count = 0;
+    for i in 0..data.len().saturating_sub(1) {
+        if data[i] == 0xFF && (0xD0..=0xD7).contains(&data[i + 1]) {
+            count += 1;
+            if count == n {
+                return Some(i + 2);
             }
+        }
+    }
+    None
+}
+
+/// DC prediction of each component.
+type PrevDC = [i16; 4];
+
+/// Where a `ScanReader` stopped, kept to resume decoding once more data
+/// is available.
+#[derive(Debug, Clone, Copy)]
+pub struct ScanState {
+    byte: usize,
+    bit: u8,
+    n_mcus: usize,
+    prev_dc: PrevDC,
+    pending_restart: bool,
+}
+
+impl ScanState {
+    /// Bytes of the data the reader is done with. The byte before the
+    /// current one is not counted: it tells a stuffed 0x00 from data.
+    pub fn consumed(&self) -> usize {
+        self.byte.saturating_sub(1)
+    }
+
+    /// Same state, for the data without its first `n` bytes.
+    pub fn skip(&mut self, n: usize) {
+        self.byte -= n;
+    }
+}
+
+/// What a `ScanReader` takes from the headers, owned so that it can be kept
+/// while the data it applies to is still arriving.
+#[derive(Debug, Clone)]
+pub struct ScanSetup {
+    layout: McuLayout,
+    /// Component, DC and AC Huffman table indices of each block of an MCU.
+    blocks: Vec<(usize, usize, usize)>,
+    interval: usize,
+}
+
+impl ScanSetup {
+    /// Fails on segments `parsing::validate` rejects for a missing header
+    /// or Huffman table.
+    pub fn new(segments: &Segments) -> Result<ScanSetup, Error> {
+        let sof = segments.start_of_frame.as_ref().ok_or(Error::Format("Missing baseline frame header"))?;
+        let sos = segments.start_of_scan.as_ref().ok_or(Error::Format("Missing scan header"))?;
+        if sos.components.len() != sof.components.len() {
+            return Err(Error::Format("Scans of part of the components not supported"));
+        }
+        let layout = McuLayout::new(sof);
+
+        let find = |class: Class, destination: &Destination| {
+            segments.huffman_tables
+                .iter()
+                .position(|t| t.class == class && t.destination == *destination)
+                .ok_or(Error::Format("Missing Huffman table"))
         };
+        let tables = sos.components
+            .iter()
+            .map(|c| Ok((find(Class::DC, &c.dc_table)?, find(Class::AC, &c.ac_table)?)))
+            .collect::<Result<Vec<(usize, usize)>, Error>>()?;
+        let blocks = layout.block_components()
+            .into_iter()
+            .map(|c| (c, tables[c].0, tables[c].1))
+            .collect();
+
+        Ok(ScanSetup {
+            layout,
+            blocks,
+            interval: segments.restart_interval as usize,
+        })
+    }
+}
+
+/// Entropy decoder of a scan, one MCU at a time. The Huffman tables are
+/// passed to each call so the reader does not borrow the segments.
+#[derive(Clone)]
+pub struct ScanReader<'a> {
+    data: &'a [u8],
+    bit_stream: huffman::BitStream<'a>,
+    setup: ScanSetup,
+    n_mcus: usize,
+    prev_dc: PrevDC,
+    /// A restart marker ends the last decoded MCU. It is only read along
+    /// with the next MCU, so a reader stopped at the end of an interval can
+    /// resume once the marker is available.
+    pending_restart: bool,
+}
+
+impl<'a> ScanReader<'a> {
+    /// Fails on segments `parsing::validate` rejects for a missing header
+    /// or Huffman table.
+    pub fn new(segments: &Segments<'a>) -> Result<ScanReader<'a>, Error> {
+        let setup = ScanSetup::new(segments)?;
+        let data = segments.start_of_scan.as_ref().map_or(&[][..], |sos| sos.data);
+        Ok(ScanReader::with_setup(setup, data))
+    }
+
+    /// Reader of the scan data `data`, from its start.
+    pub fn with_setup(setup: ScanSetup, data: &'a [u8]) -> ScanReader<'a> {
+        ScanReader {
+            data,
+            bit_stream: huffman::BitStream::new(data),
+            setup,
+            n_mcus: 0,
+            prev_dc: PrevDC::default(),
+            pending_restart: false,
+        }
+    }
+
+    pub fn layout(&self) -> &McuLayout {
+        &self.setup.layout
+    }
+
+    /// Index of the next MCU to be decoded.
+    pub fn position(&self) -> usize {
+        self.n_mcus
+    }
+
+    pub fn state(&self) -> ScanState {
+        let (byte, bit) = self.bit_stream.position();
+        ScanState {
+            byte,
+            bit,
+            n_mcus: self.n_mcus,
+            prev_dc: self.prev_dc,
+            pending_restart: self.pending_restart,
+        }
+    }
+
+    pub fn restore(&mut self, state: ScanState) {
+        self.bit_stream.seek(state.byte, state.bit);
+        self.n_mcus = state.n_mcus;
+        self.prev_dc = state.prev_dc;
+        self.pending_restart = state.pending_restart;
+    }
+
+    /// Whether all the scan data was read.
+    pub fn at_end(&self) -> bool {
+        let (byte, _) = self.bit_stream.position();
+        match self.data.get(byte..) {
+            None | Some([] | [0xFF]) => true,
+            Some(_) => false,
+        }
+    }
+
+    /// After corrupt data, skips to the next restart marker and returns the
+    /// index of the MCU decoding resumes at. The interval a marker ends is
+    /// the first one, from the current one on, with its number modulo 8.
+    /// `None` without restart intervals or marker further in the data.
+    pub fn resync(&mut self) -> Option<usize> {
+        if self.setup.interval == 0 {
+            return None;
+        }
+        let (byte, _) = self.bit_stream.position();
+        let last = if self.pending_restart { self.n_mcus - 1 } else { self.n_mcus };
+        let current = last / self.setup.interval;
+
+        let offset = (byte..self.data.len().saturating_sub(1))
+            .find(|&i| self.data[i] == 0xFF && (0xD0..=0xD7).contains(&self.data[i + 1]))?;
+        let number = (self.data[offset + 1] - 0xD0) as usize;
+        let interval = current + (number + 8 - current % 8) % 8;
+
+        self.bit_stream.seek(offset + 2, 8);
+        self.n_mcus = (interval + 1) * self.setup.interval;
+        self.prev_dc = PrevDC::default();
+        self.pending_restart = false;
+        Some(self.n_mcus)
+    }
+
+    /// Jumps to the start of the restart interval holding MCU `mcu`, if the
+    /// scan has restart intervals. Otherwise the reader is left in place.
+    pub fn seek(&mut self, mcu: usize) {
+        if let Some(n_restarts) = mcu.checked_div(self.setup.interval) {
+            if let Some(offset) = restart_offset(self.data, n_restarts) {
+                self.start_interval(n_restarts, offset);
+            }
+        }
+    }
+
+    /// Jumps to restart interval `n`, whose data starts at `offset` in the
+    /// scan data.
+    pub fn start_interval(&mut self, n: usize, offset: usize) {
+        self.bit_stream.seek(offset, 8);
+        self.n_mcus = n * self.setup.interval;
+        self.prev_dc = PrevDC::default();
+        self.pending_restart = false;
+    }
+
+    /// Reads the restart marker ending the last decoded MCU, as `next_mcu`
+    /// would. `false` if that MCU does not end an interval or the marker is
+    /// missing.
+    #[cfg(feature = "rayon")]
+    pub fn read_restart(&mut self) -> bool {
+        self.pending_restart && self.bit_stream.restart()
+    }
+
+    fn read_block(&mut self, tables: &[HuffmanTable], dc: usize, ac: usize, prev_dc: i16) -> Option<[i16; 64]> {
+        let mut block = [0; 64];
+
+        let category = tables[dc].lookup.decode(&mut self.bit_stream)?;
+        block[0] = self.bit_stream.try_get_coeff(category)?.wrapping_add(prev_dc);
+
+        let mut i = 1;
+        while i < 64 {
+            let category = tables[ac].lookup.decode(&mut self.bit_stream)?;
+            if category == 0 {
+                break;
+            }
+            i += (category as usize) >> 4;
+            if i > 63 {
+                break;
+            }
+            block[i] = self.bit_stream.try_get_coeff(category & 0x0F)?;
+            i += 1;
+        }
+        Some(block)
+    }
+
+    /// Decodes the next MCU: the blocks of each component in turn, see
+    /// `McuLayout::block_components`. `None` if the data ends or is corrupt,
+    /// including a missing restart marker.
+    pub fn next_mcu(&mut self, tables: &[HuffmanTable]) -> Option<Vec<[i16; 64]>> {
+        let mut blocks = Vec::with_capacity(self.setup.blocks.len());
+        self.next_mcu_into(tables, &mut blocks)?;
+        Some(blocks)
+    }
+
+    /// Same as `next_mcu`, appending the blocks to `blocks`. Nothing is
+    /// appended on failure.
+    pub fn next_mcu_into(&mut self, tables: &[HuffmanTable], blocks: &mut Vec<[i16; 64]>) -> Option<()> {
+        if self.pending_restart {
+            if !self.bit_stream.restart() {
+                return None;
+            }
+            self.pending_restart = false;
+            self.prev_dc = PrevDC::default();
+        }
+        let len = blocks.len();
+
+        for k in 0..self.setup.blocks.len() {
+            let (c, dc, ac) = self.setup.blocks[k];
+            let Some(block) = self.read_block(tables, dc, ac, self.prev_dc[c]) else {
+                blocks.truncate(len);
+                return None;
+            };
+            self.prev_dc[c] = block[0];
+            blocks.push(block);
+        }
+
+        self.n_mcus += 1;
+        self.pending_restart = self.setup.interval > 0 && self.n_mcus.is_multiple_of(self.setup.interval);
+        Some(())
+    }
+}
+
+/// Decodes the blocks of every MCU of the frame, stopping early if the scan
+/// data ends. Data beyond the last MCU is ignored.
+pub fn scan_blocks(segments: &Segments) -> Result<Vec<[i16; 64]>, Error> {
+    let mut blocks = Vec::new();
+    scan_blocks_into(segments, &mut blocks)?;
+    Ok(blocks)
+}
+
+/// Same as `scan_blocks`, replacing the content of `blocks` so that its
+/// allocation is reused.
+pub fn scan_blocks_into(segments: &Segments, blocks: &mut Vec<[i16; 64]>) -> Result<(), Error> {
+    blocks.clear();
+    let mut reader = ScanReader::new(segments)?;
+    let total = reader.layout().mcus_wide * reader.layout().mcus_high;
+    while reader.position() < total && reader.next_mcu_into(&segments.huffman_tables, blocks).is_some() {}
+    Ok(())
+}
+
+/// Decodes the MCUs `first..last`, in raster order, passing the index and
+/// blocks of each to `f`. When the scan has restart intervals, decoding
+/// starts at the interval holding `first` instead of the beginning of the
+/// scan. The blocks share one buffer, so only the MCUs `f` keeps take
+/// memory. Returns the index of the MCU after the last decoded one, which is
+/// lower than `last` if the scan data ends before.
+pub fn scan_mcu_range(segments: &Segments, first: usize, last: usize, mut f: impl FnMut(usize, &[[i16; 64]])) -> Result<usize, Error> {
+    let mut blocks: Vec<[i16; 64]> = Vec::new();
+
+    let mut reader = ScanReader::new(segments)?;
+    reader.seek(first);
+
+    while reader.position() < last {
+        let mcu = reader.position();
+        blocks.clear();
+        if reader.next_mcu_into(&segments.huffman_tables, &mut blocks).is_none() {
+            break;
+        }
+        if mcu >= first {
+            f(mcu, &blocks);
+        }
     }
 
-    blocks
+    Ok(reader.position())
 }
 
 
@@ -98,34 +402,29 @@ pub fn scan_blocks(segments: &mut Segments) -> Vec<[i16; 64]> {
 #[cfg(test)]
 mod test {
     use std::fs;
+    use crate::fixtures::fixture;
     use crate::parsing::parse;
-    use crate::huffman::BitStream;
-    // use super::*;
+    use super::*;
 
     #[test]
-    fn test_huffman_and_bits() {
-        let bytes = [0b11110111, 0b11110100];
-
-        let mut bit_stream = BitStream::new(&bytes);
-        let data = fs::read("img/white_square.jpg").expect("Failed to read image");
-        let tree = &mut parse(&data).huffman_tables[0].tree;
-        let mut value = 0;
-        for _ in 0..16 {
-            let bit = bit_stream.next_bit().unwrap();
-            if let Ok(Some(x)) = tree.forward(bit) {
-                value = bit_stream.get_coeff(x);
-                break
-            };
-        }
-        assert_eq!(value, 127);
+    fn test_missing_huffman_table() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let mut segments = parse(&data);
+        assert!(ScanReader::new(&segments).is_ok());
 
-        for _ in 0..16 {
-            let bit = bit_stream.next_bit().unwrap();
-            if let Ok(Some(x)) = tree.forward(bit) {
-                value = bit_stream.get_coeff(x);
-                break
-            };
-        }
-        assert_eq!(value, -1);
+        segments.huffman_tables.retain(|t| t.class != Class::AC);
+        assert!(matches!(ScanReader::new(&segments), Err(Error::Format("Missing Huffman table"))));
+        assert_eq!(scan_blocks(&segments), Err(Error::Format("Missing Huffman table")));
+    }
+
+    #[test]
+    fn test_scan_mcu_range() {
+        let data = fixture(40, 24, &[(2, 2), (1, 1), (1, 1)], 90, 2);
+        let segments = parse(&data);
+        let all = scan_blocks(&segments).unwrap();
+        let mut mcus = Vec::new();
+        let end = scan_mcu_range(&segments, 3, 5, |mcu, blocks| mcus.push((mcu, blocks.to_vec()))).unwrap();
+        assert_eq!(end, 5);
+        assert_eq!(mcus, vec![(3, all[18..24].to_vec()), (4, all[24..30].to_vec())]);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,314 @@
+//! Vectorized IDCT and colour conversion. Each kernel performs the same
+//! float operations, in the same order, as the scalar code (multiplications
+//! and additions are never fused), so they all give bit-identical results.
+
+use std::sync::OnceLock;
+
+#[cfg(target_arch = "aarch64")]
+use std::arch::aarch64::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use crate::transf::ycbcr_to_rgb;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Sse2,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+/// Every kernel the CPU supports, the fastest last.
+pub fn available() -> Vec<Kernel> {
+    #[allow(unused_mut)]
+    let mut kernels = vec![Kernel::Scalar];
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse2") {
+            kernels.push(Kernel::Sse2);
+        }
+        if is_x86_feature_detected!("avx2") {
+            kernels.push(Kernel::Avx2);
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        kernels.push(Kernel::Neon);
+    }
+    kernels
+}
+
+/// Fastest kernel the CPU supports, detected once.
+pub fn detected() -> Kernel {
+    static KERNEL: OnceLock<Kernel> = OnceLock::new();
+    *KERNEL.get_or_init(|| *available().last().unwrap())
+}
+
+/// 2-D IDCT as two passes of 1-D ones, `basis[u][x]` being the weight of
+/// frequency `u` in sample `x`: rows first, then columns, then the level
+/// shift. Only call with a kernel listed by `available`.
+pub fn idct(kernel: Kernel, input: &[[f32; 8]; 8], basis: &[[f32; 8]; 8]) -> [[f32; 8]; 8] {
+    match kernel {
+        Kernel::Scalar => idct_scalar(input, basis),
+        // SAFETY: the CPU supports the kernel's instructions
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Sse2 => unsafe { idct_sse2(input, basis) },
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx2 => unsafe { idct_avx2(input, basis) },
+        #[cfg(target_arch = "aarch64")]
+        Kernel::Neon => unsafe { idct_neon(input, basis) },
+    }
+}
+
+/// `ycbcr_to_rgb` over a row of pixels. Only call with a kernel listed by
+/// `available`.
+pub fn ycbcr_row(kernel: Kernel, y: &[f32], cb: &[f32], cr: &[f32], out: &mut [[u8; 3]]) {
+    let done = match kernel {
+        Kernel::Scalar => 0,
+        // SAFETY: the CPU supports the kernel's instructions
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Sse2 => unsafe { ycbcr_sse2(y, cb, cr, out) },
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx2 => unsafe { ycbcr_avx2(y, cb, cr, out) },
+        #[cfg(target_arch = "aarch64")]
+        Kernel::Neon => unsafe { ycbcr_neon(y, cb, cr, out) },
+    };
+    // Pixels left over by the vector kernels
+    for (i, pixel) in out.iter_mut().enumerate().skip(done) {
+        *pixel = ycbcr_to_rgb(y[i], cb[i], cr[i]);
+    }
+}
+
+fn idct_scalar(input: &[[f32; 8]; 8], basis: &[[f32; 8]; 8]) -> [[f32; 8]; 8] {
+    let mut rows = [[0.; 8]; 8];
+    for (row, coeffs) in rows.iter_mut().zip(input) {
+        for (&coeff, weights) in coeffs.iter().zip(basis) {
+            for (value, &weight) in row.iter_mut().zip(weights) {
+                *value += coeff * weight;
+            }
+        }
+    }
+
+    let mut res = [[0.; 8]; 8];
+    for (y, out) in res.iter_mut().enumerate() {
+        for (row, weights) in rows.iter().zip(basis) {
+            for (value, &sample) in out.iter_mut().zip(row) {
+                *value += weights[y] * sample;
+            }
+        }
+        for value in out.iter_mut() {
+            *value += 128.;
+        }
+    }
+    res
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn idct_sse2(input: &[[f32; 8]; 8], basis: &[[f32; 8]; 8]) -> [[f32; 8]; 8] {
+    let load = |row: &[f32; 8]| (_mm_loadu_ps(row.as_ptr()), _mm_loadu_ps(row[4..].as_ptr()));
+    let weights = basis.map(|row| load(&row));
+
+    let mut rows = [(_mm_setzero_ps(), _mm_setzero_ps()); 8];
+    for (row, coeffs) in rows.iter_mut().zip(input) {
+        for (&coeff, &(lo, hi)) in coeffs.iter().zip(&weights) {
+            let coeff = _mm_set1_ps(coeff);
+            *row = (_mm_add_ps(row.0, _mm_mul_ps(coeff, lo)), _mm_add_ps(row.1, _mm_mul_ps(coeff, hi)));
+        }
+    }
+
+    let mut res = [[0.; 8]; 8];
+    for (y, out) in res.iter_mut().enumerate() {
+        let (mut lo, mut hi) = (_mm_setzero_ps(), _mm_setzero_ps());
+        for (&(row_lo, row_hi), weights) in rows.iter().zip(basis) {
+            let weight = _mm_set1_ps(weights[y]);
+            lo = _mm_add_ps(lo, _mm_mul_ps(weight, row_lo));
+            hi = _mm_add_ps(hi, _mm_mul_ps(weight, row_hi));
+        }
+        let shift = _mm_set1_ps(128.);
+        _mm_storeu_ps(out.as_mut_ptr(), _mm_add_ps(lo, shift));
+        _mm_storeu_ps(out[4..].as_mut_ptr(), _mm_add_ps(hi, shift));
+    }
+    res
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn idct_avx2(input: &[[f32; 8]; 8], basis: &[[f32; 8]; 8]) -> [[f32; 8]; 8] {
+    let weights = basis.map(|row| _mm256_loadu_ps(row.as_ptr()));
+
+    let mut rows = [_mm256_setzero_ps(); 8];
+    for (row, coeffs) in rows.iter_mut().zip(input) {
+        for (&coeff, &weights) in coeffs.iter().zip(&weights) {
+            *row = _mm256_add_ps(*row, _mm256_mul_ps(_mm256_set1_ps(coeff), weights));
+        }
+    }
+
+    let mut res = [[0.; 8]; 8];
+    for (y, out) in res.iter_mut().enumerate() {
+        let mut acc = _mm256_setzero_ps();
+        for (&row, weights) in rows.iter().zip(basis) {
+            acc = _mm256_add_ps(acc, _mm256_mul_ps(_mm256_set1_ps(weights[y]), row));
+        }
+        _mm256_storeu_ps(out.as_mut_ptr(), _mm256_add_ps(acc, _mm256_set1_ps(128.)));
+    }
+    res
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn idct_neon(input: &[[f32; 8]; 8], basis: &[[f32; 8]; 8]) -> [[f32; 8]; 8] {
+    let load = |row: &[f32; 8]| (vld1q_f32(row.as_ptr()), vld1q_f32(row[4..].as_ptr()));
+    let weights = basis.map(|row| load(&row));
+
+    let mut rows = [(vdupq_n_f32(0.), vdupq_n_f32(0.)); 8];
+    for (row, coeffs) in rows.iter_mut().zip(input) {
+        for (&coeff, &(lo, hi)) in coeffs.iter().zip(&weights) {
+            // vmulq then vaddq rather than vmlaq, which may be fused
+            *row = (vaddq_f32(row.0, vmulq_n_f32(lo, coeff)), vaddq_f32(row.1, vmulq_n_f32(hi, coeff)));
+        }
+    }
+
+    let mut res = [[0.; 8]; 8];
+    for (y, out) in res.iter_mut().enumerate() {
+        let (mut lo, mut hi) = (vdupq_n_f32(0.), vdupq_n_f32(0.));
+        for (&(row_lo, row_hi), weights) in rows.iter().zip(basis) {
+            lo = vaddq_f32(lo, vmulq_n_f32(row_lo, weights[y]));
+            hi = vaddq_f32(hi, vmulq_n_f32(row_hi, weights[y]));
+        }
+        let shift = vdupq_n_f32(128.);
+        vst1q_f32(out.as_mut_ptr(), vaddq_f32(lo, shift));
+        vst1q_f32(out[4..].as_mut_ptr(), vaddq_f32(hi, shift));
+    }
+    res
+}
+
+/// Writes converted pixels from `lanes` channel values, already clamped
+/// and truncated, of consecutive pixels.
+fn store_pixels(red: &[i32], green: &[i32], blue: &[i32], out: &mut [[u8; 3]]) {
+    for (k, pixel) in out.iter_mut().enumerate() {
+        *pixel = [red[k] as u8, green[k] as u8, blue[k] as u8];
+    }
+}
+
+/// Converts as many pixels as fit in whole vectors, returning their number.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn ycbcr_sse2(y: &[f32], cb: &[f32], cr: &[f32], out: &mut [[u8; 3]]) -> usize {
+    let channel = |value: __m128| {
+        let mut res = [0; 4];
+        let value = _mm_cvttps_epi32(_mm_min_ps(_mm_max_ps(value, _mm_setzero_ps()), _mm_set1_ps(255.)));
+        _mm_storeu_si128(res.as_mut_ptr() as *mut __m128i, value);
+        res
+    };
+
+    let n = out.len() / 4 * 4;
+    for i in (0..n).step_by(4) {
+        let lum = _mm_loadu_ps(y[i..i + 4].as_ptr());
+        let cb = _mm_sub_ps(_mm_loadu_ps(cb[i..i + 4].as_ptr()), _mm_set1_ps(128.));
+        let cr = _mm_sub_ps(_mm_loadu_ps(cr[i..i + 4].as_ptr()), _mm_set1_ps(128.));
+
+        let red = _mm_add_ps(lum, _mm_mul_ps(_mm_set1_ps(1.402), cr));
+        let green = _mm_add_ps(lum, _mm_mul_ps(_mm_set1_ps(-0.344136), cb));
+        let green = _mm_sub_ps(green, _mm_mul_ps(_mm_set1_ps(0.714136), cr));
+        let blue = _mm_add_ps(lum, _mm_mul_ps(_mm_set1_ps(1.772), cb));
+        store_pixels(&channel(red), &channel(green), &channel(blue), &mut out[i..i + 4]);
+    }
+    n
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn ycbcr_avx2(y: &[f32], cb: &[f32], cr: &[f32], out: &mut [[u8; 3]]) -> usize {
+    let channel = |value: __m256| {
+        let mut res = [0; 8];
+        let value = _mm256_cvttps_epi32(_mm256_min_ps(_mm256_max_ps(value, _mm256_setzero_ps()), _mm256_set1_ps(255.)));
+        _mm256_storeu_si256(res.as_mut_ptr() as *mut __m256i, value);
+        res
+    };
+
+    let n = out.len() / 8 * 8;
+    for i in (0..n).step_by(8) {
+        let lum = _mm256_loadu_ps(y[i..i + 8].as_ptr());
+        let cb = _mm256_sub_ps(_mm256_loadu_ps(cb[i..i + 8].as_ptr()), _mm256_set1_ps(128.));
+        let cr = _mm256_sub_ps(_mm256_loadu_ps(cr[i..i + 8].as_ptr()), _mm256_set1_ps(128.));
+
+        let red = _mm256_add_ps(lum, _mm256_mul_ps(_mm256_set1_ps(1.402), cr));
+        let green = _mm256_add_ps(lum, _mm256_mul_ps(_mm256_set1_ps(-0.344136), cb));
+        let green = _mm256_sub_ps(green, _mm256_mul_ps(_mm256_set1_ps(0.714136), cr));
+        let blue = _mm256_add_ps(lum, _mm256_mul_ps(_mm256_set1_ps(1.772), cb));
+        store_pixels(&channel(red), &channel(green), &channel(blue), &mut out[i..i + 8]);
+    }
+    n
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn ycbcr_neon(y: &[f32], cb: &[f32], cr: &[f32], out: &mut [[u8; 3]]) -> usize {
+    let channel = |value: float32x4_t| {
+        let mut res = [0; 4];
+        let value = vcvtq_s32_f32(vminq_f32(vmaxq_f32(value, vdupq_n_f32(0.)), vdupq_n_f32(255.)));
+        vst1q_s32(res.as_mut_ptr(), value);
+        res
+    };
+
+    let n = out.len() / 4 * 4;
+    for i in (0..n).step_by(4) {
+        let lum = vld1q_f32(y[i..i + 4].as_ptr());
+        let cb = vsubq_f32(vld1q_f32(cb[i..i + 4].as_ptr()), vdupq_n_f32(128.));
+        let cr = vsubq_f32(vld1q_f32(cr[i..i + 4].as_ptr()), vdupq_n_f32(128.));
+
+        let red = vaddq_f32(lum, vmulq_n_f32(cr, 1.402));
+        let green = vaddq_f32(lum, vmulq_n_f32(cb, -0.344136));
+        let green = vsubq_f32(green, vmulq_n_f32(cr, 0.714136));
+        let blue = vaddq_f32(lum, vmulq_n_f32(cb, 1.772));
+        store_pixels(&channel(red), &channel(green), &channel(blue), &mut out[i..i + 4]);
+    }
+    n
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Deterministic pseudo-random values in `range`.
+    fn values(n: usize, seed: u32, range: std::ops::Range<f32>) -> Vec<f32> {
+        let mut state = seed;
+        (0..n)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                range.start + (state >> 8) as f32 / (1 << 24) as f32 * (range.end - range.start)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_idct_kernels() {
+        let basis = crate::transf::idct_basis();
+        for seed in 0..50 {
+            let coeffs = values(64, seed, -1024.0..1024.0);
+            let input: [[f32; 8]; 8] = std::array::from_fn(|v| std::array::from_fn(|u| coeffs[8 * v + u].round()));
+            let expected = idct(Kernel::Scalar, &input, basis);
+            for kernel in available() {
+                assert_eq!(idct(kernel, &input, basis), expected, "{kernel:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_ycbcr_kernels() {
+        // Out of range samples included, and a length leaving a remainder
+        let (y, cb, cr) = (values(203, 1, -40.0..300.0), values(203, 2, -40.0..300.0), values(203, 3, -40.0..300.0));
+        let mut expected = vec![[0; 3]; 203];
+        ycbcr_row(Kernel::Scalar, &y, &cb, &cr, &mut expected);
+        for kernel in available() {
+            let mut out = vec![[0; 3]; 203];
+            ycbcr_row(kernel, &y, &cb, &cr, &mut out);
+            assert_eq!(out, expected, "{kernel:?}");
+        }
+    }
+}
@@ -0,0 +1,494 @@
+use std::io::{self, Read};
+
+use crate::error::Error;
+use crate::info;
+use crate::limits::{self, check_limits, DecoderLimits};
+use crate::options::DecodeOptions;
+use crate::parsing::{self, HuffmanTable, Segments};
+use crate::scan::{McuLayout, ScanReader, ScanSetup, ScanState};
+use crate::transf::{self, ColorConversion, McuRow, McuSamples, Scale, Upsampling};
+
+/// Decodes and inverse transforms the MCUs of the next MCU row. `None` if
+/// the scan data ends before the row is complete or cannot be decoded.
+fn decode_samples(reader: &mut ScanReader, huffman_tables: &[HuffmanTable], tables: &[[u8; 64]]) -> Option<Vec<McuSamples>> {
+    let h_mcus = reader.layout().mcus_wide;
+
+    let mut mcus = Vec::with_capacity(h_mcus);
+    for _ in 0..h_mcus {
+        let blocks = reader.next_mcu(huffman_tables)?;
+        mcus.push(transf::blocks_to_samples(&blocks, tables, Scale::Full));
+    }
+    Some(mcus)
+}
+
+/// Converts MCU row `row`, whose neighbours were decoded as well, with
+/// `convert`, cropped to the image.
+fn convert_row<T: Clone>(
+    layout: &McuLayout,
+    above: Option<&[McuSamples]>,
+    current: &[McuSamples],
+    below: Option<&[McuSamples]>,
+    row: usize,
+    convert: impl FnOnce(&McuRow) -> Vec<Vec<T>>,
+) -> Vec<Vec<T>> {
+    let mcu_row = McuRow {
+        above,
+        current,
+        below,
+        first_col: 0,
+        row,
+        layout,
+        scale: Scale::Full,
+    };
+    let (_, mcu_h) = layout.mcu_size(Scale::Full);
+    let height = usize::min(mcu_h, layout.height as usize - row * mcu_h);
+    transf::crop(convert(&mcu_row), 0, 0, layout.width as usize, height)
+}
+
+/// Decodes an image one MCU row (8 to 32 pixel rows, depending on the
+/// sampling factors) at a time. Only the samples of the current MCU row and
+/// of its two neighbours, needed by the chroma upsampling, are kept in
+/// memory.
+///
+/// The rows before a truncated or corrupt one are yielded, then
+/// `Error::Truncated`, then nothing.
+pub struct RowDecoder<'a> {
+    segments: Segments<'a>,
+    reader: ScanReader<'a>,
+    tables: Vec<[u8; 64]>,
+    upsampling: Upsampling,
+    conversion: ColorConversion,
+    row: usize,
+    above: Option<Vec<McuSamples>>,
+    current: Option<Vec<McuSamples>>,
+}
+
+impl<'a> RowDecoder<'a> {
+    /// Fails on headers the decoder cannot handle or beyond the default
+    /// limits.
+    pub fn new(data: &'a [u8]) -> Result<RowDecoder<'a>, Error> {
+        RowDecoder::with_options(data, &DecodeOptions::default())
+    }
+
+    /// Same as `new`, with `options.upsampling`, `options.conversion` and
+    /// `options.limits`. The other options are not used.
+    pub fn with_options(data: &'a [u8], options: &DecodeOptions) -> Result<RowDecoder<'a>, Error> {
+        check_limits(data, &options.limits)?;
+        let segments = parsing::parse(data);
+        limits::validate(&segments, &options.limits)?;
+        RowDecoder::from_segments(segments, options.upsampling, options.conversion)
+    }
+
+    fn from_segments(segments: Segments<'a>, upsampling: Upsampling, conversion: ColorConversion) -> Result<RowDecoder<'a>, Error> {
+        let reader = ScanReader::new(&segments)?;
+        Ok(RowDecoder {
+            tables: transf::block_tables(&segments),
+            segments,
+            reader,
+            upsampling,
+            conversion,
+            row: 0,
+            above: None,
+            current: None,
+        })
+    }
+
+    pub fn width(&self) -> u16 {
+        self.segments.start_of_frame.as_ref().unwrap().width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.segments.start_of_frame.as_ref().unwrap().height
+    }
+
+    /// Number of MCU rows, i.e. of items yielded by the decoder when the
+    /// scan is complete.
+    pub fn mcu_rows(&self) -> usize {
+        self.reader.layout().mcus_high
+    }
+
+    /// Decodes the next MCU row, cropped to the image. Returns `None` once
+    /// all rows were read, and `Error::Truncated` if the scan ends early.
+    pub fn read_mcu_row(&mut self) -> Option<Result<Vec<Vec<[u8; 3]>>, Error>> {
+        let (upsampling, conversion) = (self.upsampling, self.conversion);
+        self.next_row(|mcu_row| mcu_row.to_rgb(upsampling, conversion))
+    }
+
+    /// Same as `read_mcu_row`, with the luma samples only.
+    pub(crate) fn read_luma_row(&mut self) -> Option<Result<Vec<Vec<u8>>, Error>> {
+        let upsampling = self.upsampling;
+        self.next_row(|mcu_row| mcu_row.to_luma(upsampling))
+    }
+
+    fn next_row<T: Clone>(&mut self, convert: impl FnOnce(&McuRow) -> Vec<Vec<T>>) -> Option<Result<Vec<Vec<T>>, Error>> {
+        if self.row >= self.mcu_rows() {
+            return None;
+        }
+        let current = match self.current.take() {
+            Some(current) => current,
+            None => match decode_samples(&mut self.reader, &self.segments.huffman_tables, &self.tables) {
+                Some(current) => current,
+                None => {
+                    self.row = self.mcu_rows();
+                    return Some(Err(Error::Truncated));
+                }
+            },
+        };
+        // The next row is read ahead for its chroma; if the scan ends there,
+        // the current row is still converted, with its last chroma samples
+        // repeated.
+        let below = if self.row + 1 < self.mcu_rows() {
+            decode_samples(&mut self.reader, &self.segments.huffman_tables, &self.tables)
+        } else {
+            None
+        };
+
+        let layout = self.reader.layout();
+        let rows = convert_row(layout, self.above.as_deref(), &current, below.as_deref(), self.row, convert);
+        self.above = Some(current);
+        self.current = below;
+        self.row += 1;
+        Some(Ok(rows))
+    }
+}
+
+impl Iterator for RowDecoder<'_> {
+    type Item = Result<Vec<Vec<[u8; 3]>>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_mcu_row()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    NeedMoreData,
+    /// The frame header was read during this call, more data is needed to
+    /// decode the image. Returned once, unless the whole image was fed at
+    /// once.
+    Header { width: u16, height: u16 },
+    Done,
+    /// The headers or the scan data are invalid, or the headers go beyond
+    /// the decoder limits: feeding more data is pointless.
+    Error(Error),
+}
+
+/// What the incremental decoder keeps of the headers once they are
+/// complete, so that each call only decodes the data it brings.
+struct Headers {
+    setup: ScanSetup,
+    huffman_tables: Vec<HuffmanTable>,
+    tables: Vec<[u8; 64]>,
+}
+
+impl Headers {
+    fn read(headers: &[u8], limits: &DecoderLimits) -> Result<Headers, Error> {
+        check_limits(headers, limits)?;
+        let segments = parsing::parse(headers);
+        limits::validate(&segments, limits)?;
+        let setup = ScanSetup::new(&segments)?;
+        let tables = transf::block_tables(&segments);
+        Ok(Headers {
+            setup,
+            huffman_tables: segments.huffman_tables,
+            tables,
+        })
+    }
+}
+
+/// Push decoder: bytes are fed as they arrive, headers are parsed once, as
+/// soon as they are complete, and entropy decoding resumes where it
+/// stopped, one full MCU row at a time. A row is output once the next one,
+/// needed for the chroma upsampling, is decoded as well.
+///
+/// Only the data not decoded yet is buffered: the headers are dropped once
+/// read, and the scan data of each decoded MCU row.
+#[derive(Default)]
+pub struct IncrementalDecoder {
+    /// Data not decoded yet: the headers, then the scan data from `state`.
+    buffer: Vec<u8>,
+    dimensions: Option<(u16, u16)>,
+    upsampling: Upsampling,
+    conversion: ColorConversion,
+    limits: DecoderLimits,
+    headers: Option<Headers>,
+    state: Option<ScanState>,
+    row: usize,
+    above: Option<Vec<McuSamples>>,
+    current: Option<Vec<McuSamples>>,
+    pixels: Vec<Vec<[u8; 3]>>,
+}
+
+impl IncrementalDecoder {
+    pub fn new() -> IncrementalDecoder {
+        IncrementalDecoder::default()
+    }
+
+    pub fn with_upsampling(upsampling: Upsampling) -> IncrementalDecoder {
+        IncrementalDecoder {
+            upsampling,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_limits(limits: DecoderLimits) -> IncrementalDecoder {
+        IncrementalDecoder {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Decoder with `options.upsampling`, `options.conversion` and
+    /// `options.limits`. The other options are not used.
+    pub fn with_options(options: &DecodeOptions) -> IncrementalDecoder {
+        IncrementalDecoder {
+            upsampling: options.upsampling,
+            conversion: options.conversion,
+            limits: options.limits,
+            ..Default::default()
+        }
+    }
+
+    /// Width and height, known once the frame header was fed.
+    pub fn dimensions(&self) -> Option<(u16, u16)> {
+        self.dimensions
+    }
+
+    /// Pixel rows decoded so far.
+    pub fn rows(&self) -> &[Vec<[u8; 3]>] {
+        &self.pixels
+    }
+
+    pub fn into_image(self) -> Vec<Vec<[u8; 3]>> {
+        self.pixels
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Status {
+        self.buffer.extend_from_slice(data);
+
+        // The frame limits are checked as soon as the frame header is there,
+        // before the dimensions are reported
+        let mut need_more_data = Status::NeedMoreData;
+        if self.dimensions.is_none() {
+            if let Err(err) = check_limits(&self.buffer, &self.limits) {
+                return Status::Error(err);
+            }
+            if let Ok(info) = info::probe(&self.buffer) {
+                self.dimensions = Some((info.width, info.height));
+                need_more_data = Status::Header { width: info.width, height: info.height };
+            }
+        }
+
+        if self.headers.is_none() {
+            let Some(scan_start) = parsing::headers_length(&self.buffer) else {
+                return need_more_data;
+            };
+            match Headers::read(&self.buffer[..scan_start], &self.limits) {
+                Ok(headers) => self.headers = Some(headers),
+                Err(err) => return Status::Error(err),
+            }
+            self.buffer.drain(..scan_start);
+        }
+
+        let status = self.decode_rows();
+        if let Some(state) = &mut self.state {
+            let consumed = state.consumed();
+            self.buffer.drain(..consumed);
+            state.skip(consumed);
+        }
+        match status {
+            Status::NeedMoreData => need_more_data,
+            status => status,
+        }
+    }
+
+    /// Decodes the MCU rows the buffered scan data holds. `NeedMoreData` if
+    /// it ends before the last row, an error if it is corrupt.
+    fn decode_rows(&mut self) -> Status {
+        let headers = self.headers.as_ref().unwrap();
+        let mut reader = ScanReader::with_setup(headers.setup.clone(), &self.buffer);
+        let mcu_rows = reader.layout().mcus_high;
+        if let Some(state) = self.state {
+            reader.restore(state);
+        }
+        // Short data may be completed by the next call, corrupt data not
+        let stopped = |reader: &ScanReader| {
+            if reader.at_end() {
+                Status::NeedMoreData
+            } else {
+                Status::Error(Error::Format("Corrupt scan data"))
+            }
+        };
+
+        while self.row < mcu_rows {
+            if self.current.is_none() {
+                let Some(current) = decode_samples(&mut reader, &headers.huffman_tables, &headers.tables) else {
+                    return stopped(&reader);
+                };
+                self.current = Some(current);
+                self.state = Some(reader.state());
+            }
+            let below = if self.row + 1 < mcu_rows {
+                let Some(below) = decode_samples(&mut reader, &headers.huffman_tables, &headers.tables) else {
+                    return stopped(&reader);
+                };
+                self.state = Some(reader.state());
+                Some(below)
+            } else {
+                None
+            };
+
+            let current = self.current.take().unwrap();
+            let (upsampling, conversion) = (self.upsampling, self.conversion);
+            let rows = convert_row(reader.layout(), self.above.as_deref(), &current, below.as_deref(), self.row, |mcu_row| {
+                mcu_row.to_rgb(upsampling, conversion)
+            });
+            self.pixels.extend(rows);
+            self.above = Some(current);
+            self.current = below;
+            self.row += 1;
+        }
+        Status::Done
+    }
+}
+
+/// Pull decoder: reads `reader` chunk by chunk until the image is complete.
+pub fn decode_reader<R: Read>(reader: R) -> io::Result<Vec<Vec<[u8; 3]>>> {
+    decode_reader_with(reader, &DecodeOptions::default())
+}
+
+/// Same as `decode_reader`, with `options.upsampling`, `options.conversion`
+/// and `options.limits`.
+/// The other options are not used.
+pub fn decode_reader_with<R: Read>(mut reader: R, options: &DecodeOptions) -> io::Result<Vec<Vec<[u8; 3]>>> {
+    let mut decoder = IncrementalDecoder::with_options(options);
+    let mut chunk = [0; 1 << 16];
+
+    loop {
+        let n = reader.read(&mut chunk)?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated JPEG data"));
+        }
+        match decoder.feed(&chunk[..n]) {
+            Status::NeedMoreData | Status::Header { .. } => {}
+            Status::Done => return Ok(decoder.into_image()),
+            Status::Error(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use crate::decode_binary;
+    use crate::fixtures::fixture;
+    use super::*;
+
+    #[test]
+    fn test_row_decoder_truncated() {
+        let data = fixture(40, 64, &[(2, 2), (1, 1), (1, 1)], 90, 0);
+        let full = crate::decode_binary(&data).unwrap();
+
+        // The rows before the truncated one are still decoded
+        let scan_start = parsing::headers_length(&data).unwrap();
+        let truncated = &data[..(scan_start + data.len()) / 2];
+        let mut decoder = RowDecoder::new(truncated).unwrap();
+        assert_eq!(decoder.read_mcu_row().unwrap().unwrap(), full[..16]);
+        let rows: Vec<_> = decoder.by_ref().collect();
+        assert!(rows.len() < 3);
+        assert_eq!(rows.last(), Some(&Err(Error::Truncated)));
+        assert_eq!(decoder.read_mcu_row(), None);
+    }
+
+    #[test]
+    fn test_incremental_decoder_buffer() {
+        let data = fixture(64, 96, &[(2, 2), (1, 1), (1, 1)], 90, 0);
+        let scan_start = parsing::headers_length(&data).unwrap();
+
+        // Only the scan data of the rows not decoded yet is kept
+        let mut decoder = IncrementalDecoder::new();
+        let mut largest = 0;
+        for chunk in data.chunks(64) {
+            decoder.feed(chunk);
+            if decoder.headers.is_some() {
+                largest = largest.max(decoder.buffer.len());
+            }
+        }
+        assert!(largest < (data.len() - scan_start) / 2, "{largest}");
+        assert_eq!(decoder.into_image(), crate::decode_binary(&data).unwrap());
+    }
+
+    #[test]
+    fn test_incremental_decoder_corrupt() {
+        let data = fixture(40, 64, &[(2, 2), (1, 1), (1, 1)], 90, 0);
+        let scan_start = parsing::headers_length(&data).unwrap();
+        let middle = (scan_start + data.len()) / 2;
+
+        // Ones only, which no Huffman code is made of
+        let mut corrupt = data.clone();
+        corrupt[middle..middle + 16].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        let mut decoder = IncrementalDecoder::new();
+        assert_eq!(decoder.feed(&corrupt), Status::Error(Error::Format("Corrupt scan data")));
+
+        // A scan cut short by the end of the image
+        let mut decoder = IncrementalDecoder::new();
+        assert_eq!(decoder.feed(&[&data[..middle], &[0xFF, 0xD9][..]].concat()), Status::Error(Error::Format("Corrupt scan data")));
+
+        // Whereas a scan cut short by the end of the data needs more of it
+        let mut decoder = IncrementalDecoder::new();
+        assert_eq!(decoder.feed(&data[..middle]), Status::Header { width: 40, height: 64 });
+        assert_eq!(decoder.feed(&data[middle..]), Status::Done);
+    }
+
+    #[test]
+    fn test_row_decoder() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let full = decode_binary(&data).unwrap();
+
+        let decoder = RowDecoder::new(&data).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (34, 16));
+        let rows = decoder.collect::<Result<Vec<_>, _>>().unwrap().concat();
+        assert_eq!(rows, full);
+    }
+
+    #[test]
+    fn test_incremental_decoder() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let full = decode_binary(&data).unwrap();
+
+        // The dimensions are reported as soon as the frame header is there,
+        // before the other tables and the scan header
+        let sof = data.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        let mut decoder = IncrementalDecoder::new();
+        let mut chunks = data.chunks(7);
+        let mut fed = 0;
+        let header = loop {
+            let chunk = chunks.next().unwrap();
+            fed += chunk.len();
+            match decoder.feed(chunk) {
+                Status::NeedMoreData => assert!(decoder.dimensions().is_none()),
+                status => break status,
+            }
+        };
+        assert_eq!(header, Status::Header { width: 34, height: 16 });
+        assert_eq!(decoder.dimensions(), Some((34, 16)));
+        assert!(fed < sof + 19 + 7 && fed < parsing::headers_length(&data).unwrap());
+
+        let mut status = Status::NeedMoreData;
+        for chunk in chunks {
+            status = decoder.feed(chunk);
+        }
+        assert_eq!(status, Status::Done);
+        assert_eq!(decoder.into_image(), full);
+
+        assert_eq!(decode_reader(&data[..]).unwrap(), full);
+        assert!(decode_reader(&data[..data.len() / 2]).is_err());
+
+        // Restart markers split across feeds
+        let data = fixture(40, 24, &[(2, 1), (1, 1), (1, 1)], 90, 2);
+        let mut decoder = IncrementalDecoder::new();
+        let status: Vec<Status> = data.chunks(1).map(|byte| decoder.feed(byte)).collect();
+        assert_eq!(status.last(), Some(&Status::Done));
+        assert_eq!(decoder.into_image(), decode_binary(&data).unwrap());
+    }
+}
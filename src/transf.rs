@@ -1,8 +1,13 @@
+use crate::error::Error;
 use crate::scan;
+use crate::parallel;
 use crate::parsing;
+use crate::simd;
+use std::ops::Range;
+use std::sync::OnceLock;
 
 
-const ZIGZAG: [[usize; 8]; 8] = [
+pub const ZIGZAG: [[usize; 8]; 8] = [
     [ 0,  1,  5,  6, 14, 15, 27, 28],
     [ 2,  4,  7, 13, 16, 26, 29, 42],
     [ 3,  8, 12, 17, 25, 30, 41, 43],
@@ -15,23 +20,54 @@ const ZIGZAG: [[usize; 8]; 8] = [
 
 const PI: f32 = std::f32::consts::PI;
 
-pub fn dequantize(values: &[i16; 64], table: &[u8; 64]) -> [[i32; 8]; 8] {
+/// Output scale of the decoded image, applied while inverse transforming
+/// each block rather than by resizing the full-size result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scale {
+    #[default]
+    Full,
+    Half,
+    Quarter,
+    Eighth,
+}
+
+impl Scale {
+    /// Side of an 8x8 block once scaled.
+    pub fn block_size(self) -> usize {
+        match self {
+            Scale::Full => 8,
+            Scale::Half => 4,
+            Scale::Quarter => 2,
+            Scale::Eighth => 1,
+        }
+    }
+
+    /// Size of a `len` pixels dimension once scaled, rounded up.
+    pub fn scaled(self, len: u16) -> u16 {
+        let denom = 8 / self.block_size() as u32;
+        u32::div_ceil(len as u32, denom) as u16
+    }
+}
+
+pub fn dequantize<T: Copy + Into<i32>>(values: &[i16; 64], table: &[T; 64]) -> [[i32; 8]; 8] {
     let mut res = [[0; 8]; 8];
 
     for (v, row) in ZIGZAG.iter().enumerate() {
         for (u, &i) in row.iter().enumerate() {
-            res[v][u] = values[i] as i32 * table[i] as i32;
+            res[v][u] = values[i] as i32 * table[i].into();
         }
     }
     res
 }
 
+/// C(u) of the IDCT: 1/sqrt(2) for 0, 1 otherwise.
+fn cu(u: usize) -> f32 {
+    if u == 0 { std::f32::consts::FRAC_1_SQRT_2 } else { 1. }
+}
+
+/// C(u) C(v) of the IDCT.
 fn cu_cv(u: usize, v: usize) -> f32 {
-    if u == 0 && v == 0 {
-        0.5
-    } else {
-        1.
-    }
+    cu(u) * cu(v)
 }
 
 
@@ -39,27 +75,56 @@ fn cos(a: usize, b: usize) -> f32 {
     f32::cos((2. * a as f32 + 1.)*b as f32 * PI /16.)
 }
 
-fn syx(y: usize, x: usize, svu: [[i32; 8]; 8]) -> f32 {
-    let mut res = 0.;
-    for (v, row) in svu.iter().enumerate() {
-        for (u, &value) in row.iter().enumerate() {
-            res += cu_cv(u, v) * value as f32 * cos(x, u) * cos(y, v);
-        }
-    }
-    res / 4.
+/// Weight of frequency `u` in sample `x` of a 1-D IDCT, C(u) cos((2x + 1)
+/// u pi / 16) / 2, so that a pass along each direction gives the 2-D one.
+pub fn idct_basis() -> &'static [[f32; 8]; 8] {
+    static BASIS: OnceLock<[[f32; 8]; 8]> = OnceLock::new();
+    BASIS.get_or_init(|| std::array::from_fn(|u| std::array::from_fn(|x| cu(u) * cos(x, u) / 2.)))
 }
 
+/// Separable IDCT, on the fastest vector instructions the CPU supports.
 pub fn idct(input: [[i32; 8]; 8]) -> [[f32; 8]; 8] {
+    let input = input.map(|row| row.map(|value| value as f32));
+    simd::idct(simd::detected(), &input, idct_basis())
+}
+
+fn cos_n(a: usize, b: usize, n: usize) -> f32 {
+    f32::cos((2. * a as f32 + 1.)*b as f32 * PI / (2 * n) as f32)
+}
+
+/// Reduced IDCT: only the `n`x`n` lowest frequencies are used to produce an
+/// `n`x`n` block, stored in the top-left corner of the result.
+pub fn idct_scaled(input: [[i32; 8]; 8], n: usize) -> [[f32; 8]; 8] {
+    if n == 8 {
+        return idct(input);
+    }
+
     let mut res = [[0.; 8]; 8];
+    if n == 1 {
+        res[0][0] = input[0][0] as f32 / 8. + 128.;
+        return res;
+    }
 
-    for (y, row) in res.iter_mut().enumerate() {
-        for (x, value) in row.iter_mut().enumerate() {
-            *value = syx(y, x, input) + 128.;
+    for (y, row) in res.iter_mut().take(n).enumerate() {
+        for (x, value) in row.iter_mut().take(n).enumerate() {
+            let mut sum = 0.;
+            for (v, coeffs) in input.iter().take(n).enumerate() {
+                for (u, &coeff) in coeffs.iter().take(n).enumerate() {
+                    sum += cu_cv(u, v) * coeff as f32 * cos_n(x, u, n) * cos_n(y, v, n);
+                }
+            }
+            *value = sum / 4. + 128.;
         }
-    } 
+    }
     res
 }
 
+/// Luma of an RGB pixel, with the weights of ITU-R BT.601, for output
+/// formats that are grayscale.
+pub fn rgb_to_luma([r, g, b]: [u8; 3]) -> u8 {
+    ((299 * r as u32 + 587 * g as u32 + 114 * b as u32 + 500) / 1000) as u8
+}
+
 pub fn ycbcr_to_rgb(y: f32, cb: f32, cr: f32) -> [u8; 3] {
     let cb = cb - 128.;
     let cr = cr - 128.;
@@ -73,80 +138,550 @@ pub fn ycbcr_to_rgb(y: f32, cb: f32, cr: f32) -> [u8; 3] {
     [red, green, blue]
 }
 
-fn lum_idx(i: usize, j: usize) -> usize {
-    (j >> 3) + ((i >> 3) << 1)
+/// How the YCbCr samples are converted to RGB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorConversion {
+    /// `ycbcr_to_rgb` on the unrounded samples, vectorized when the CPU
+    /// allows.
+    #[default]
+    Float,
+    /// `ycbcr_to_rgb_fixed` on the samples rounded to 8 bits, as libjpeg
+    /// does after the IDCT. This is not bit-exact with libjpeg: the IDCT
+    /// and the upsampling are still floating point, so the pixels may
+    /// differ from libjpeg's by the rounding of the samples.
+    FixedPoint,
 }
 
-pub fn mcu_to_rgb(mcu: Vec<[[f32; 8]; 8]>) -> [[[u8; 3]; 16]; 16] {
-    let mut ycbcr = [[[0; 3]; 16]; 16];
-    for i in 0..16 {
-        for j in 0..16 {
-            let lum = mcu[lum_idx(i, j)][i % 8][j % 8];
-            let cb = mcu[4][i / 2][j / 2];
-            let cr = mcu[5][i / 2][j / 2];
-            ycbcr[i][j] = ycbcr_to_rgb(lum, cb, cr);
+const SCALEBITS: u32 = 16;
+const ONE_HALF: i32 = 1 << (SCALEBITS - 1);
+
+fn fix(x: f64) -> i32 {
+    (x * (1 << SCALEBITS) as f64 + 0.5) as i32
+}
+
+/// libjpeg's conversion tables, indexed by the Cb or Cr sample: the red and
+/// blue offsets, already rounded, and the green ones, still scaled up by
+/// `SCALEBITS` so that their sum is rounded once.
+struct YccTables {
+    cr_r: [i32; 256],
+    cb_b: [i32; 256],
+    cr_g: [i32; 256],
+    cb_g: [i32; 256],
+}
+
+fn ycc_tables() -> &'static YccTables {
+    static TABLES: OnceLock<YccTables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let mut tables = YccTables { cr_r: [0; 256], cb_b: [0; 256], cr_g: [0; 256], cb_g: [0; 256] };
+        for i in 0..256 {
+            let x = i as i32 - 128;
+            tables.cr_r[i] = (fix(1.40200) * x + ONE_HALF) >> SCALEBITS;
+            tables.cb_b[i] = (fix(1.77200) * x + ONE_HALF) >> SCALEBITS;
+            tables.cr_g[i] = -fix(0.71414) * x;
+            tables.cb_g[i] = -fix(0.34414) * x + ONE_HALF;
         }
+        tables
+    })
+}
+
+/// Fixed-point YCbCr to RGB conversion with lookup tables, following the
+/// arithmetic of libjpeg's `jdcolor.c`.
+pub fn ycbcr_to_rgb_fixed(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let tables = ycc_tables();
+    let (y, cb, cr) = (y as i32, cb as usize, cr as usize);
+    let limit = |value: i32| value.clamp(0, 255) as u8;
+    [
+        limit(y + tables.cr_r[cr]),
+        limit(y + ((tables.cb_g[cb] + tables.cr_g[cr]) >> SCALEBITS)),
+        limit(y + tables.cb_b[cb]),
+    ]
+}
+
+/// Sample rounded to 8 bits, halves up.
+fn sample_u8(value: f32) -> u8 {
+    (value.clamp(0., 255.) + 0.5) as u8
+}
+
+/// How the chroma planes, usually subsampled, are brought back to the
+/// luminance resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Upsampling {
+    /// Triangle filter over the nearest chroma samples, like libjpeg's
+    /// "fancy upsampling", along each direction subsampled by two. Chroma
+    /// samples are sited between luminance pixels and the filter reads
+    /// across MCU boundaries. Other ratios fall back to `Nearest`.
+    #[default]
+    Fancy,
+    /// Each chroma sample is repeated over the pixels it covers. Faster,
+    /// blockier.
+    Nearest,
+}
+
+/// The inverse transformed blocks of an MCU, before colour conversion.
+pub type McuSamples = Vec<[[f32; 8]; 8]>;
+
+/// Quantization table of each block of an MCU.
+pub fn block_tables(segments: &parsing::Segments) -> Vec<[u8; 64]> {
+    let sof = segments.start_of_frame.as_ref().unwrap();
+    let layout = scan::McuLayout::new(sof);
+
+    layout.block_components()
+        .into_iter()
+        .map(|c| {
+            let destination = sof.components[c].quantization_destination();
+            segments.quantization_tables
+                .iter()
+                .find(|t| t.destination == destination)
+                .expect("Missing quantization table")
+                .table
+        })
+        .collect()
+}
+
+/// Dequantizes and inverse transforms the blocks of an MCU, `tables` being
+/// given by `block_tables`.
+pub fn blocks_to_samples(blocks: &[[i16; 64]], tables: &[[u8; 64]], scale: Scale) -> McuSamples {
+    let mut samples = McuSamples::with_capacity(blocks.len());
+    blocks_to_samples_into(blocks, tables, scale, &mut samples);
+    samples
+}
+
+/// Same as `blocks_to_samples`, replacing the content of `samples`.
+pub fn blocks_to_samples_into(blocks: &[[i16; 64]], tables: &[[u8; 64]], scale: Scale, samples: &mut McuSamples) {
+    samples.clear();
+    samples.extend(
+        blocks
+            .iter()
+            .zip(tables)
+            .map(|(block, table)| idct_scaled(dequantize(block, table), scale.block_size())),
+    );
+}
+
+/// Samples to read along one direction for an output pixel, with the weight
+/// of the first one.
+fn taps(pos: usize, factor: usize, max: usize, upsampling: Upsampling) -> (isize, isize, f32) {
+    if upsampling == Upsampling::Fancy && 2 * factor == max {
+        let near = (pos / 2) as isize;
+        let far = if pos.is_multiple_of(2) { near - 1 } else { near + 1 };
+        (near, far, 0.75)
+    } else {
+        let near = (pos * factor / max) as isize;
+        (near, near, 1.)
     }
-    ycbcr
 }
 
-pub fn get_mcus(segments: &mut parsing::Segments) -> Vec<[[[u8; 3]; 16]; 16]> {
-    let mut res = Vec::new();
+/// A row of inverse transformed MCUs, along with the rows above and below
+/// whose samples the fancy upsampling reads.
+pub struct McuRow<'a> {
+    pub above: Option<&'a [McuSamples]>,
+    pub current: &'a [McuSamples],
+    pub below: Option<&'a [McuSamples]>,
+    /// MCU column of `current[0]`, all rows starting at the same column.
+    pub first_col: usize,
+    /// MCU row of `current`.
+    pub row: usize,
+    pub layout: &'a scan::McuLayout,
+    pub scale: Scale,
+}
+
+impl McuRow<'_> {
+    /// Sample of component `c` at column `x` of the component plane and row
+    /// `y` counted from the top of the current MCU row. Samples outside of
+    /// the image or of the available MCUs are replaced by the closest one.
+    fn sample(&self, c: usize, first_block: usize, x: isize, y: isize) -> f32 {
+        let n = self.scale.block_size();
+        let (h, v) = self.layout.factors[c];
+        let (mcu_w, mcu_h) = ((h * n) as isize, (v * n) as isize);
+        let (width, height) = self.layout.component_size(c, self.scale);
 
-    let vec = scan::scan_blocks(segments);
+        let left = self.first_col as isize * mcu_w;
+        let right = isize::min((self.first_col + self.current.len()) as isize * mcu_w, width as isize) - 1;
+        let x = x.clamp(left, right.max(left));
+        let top = self.row as isize * mcu_h;
+        let y = (top + y).clamp(0, height as isize - 1) - top;
 
-    let mut count = 0;
-    
-    let mut mcu = Vec::new();
-    let mut q_table;
-    
-    
-    for array in vec {
-        if count < 4 {
-            q_table = &segments.quantization_tables[0].table;
+        let (mcus, y) = if y < 0 {
+            match self.above {
+                Some(above) => (above, y + mcu_h),
+                None => (self.current, 0),
+            }
+        } else if y >= mcu_h {
+            match self.below {
+                Some(below) => (below, y - mcu_h),
+                None => (self.current, mcu_h - 1),
+            }
         } else {
-            q_table = &segments.quantization_tables[1].table;
-        } 
-        let mat = dequantize(&array, q_table);
-        let mat = idct(mat);
-        mcu.push(mat);
-        count += 1;
-
-        if count >= 6 {
-            res.push(mcu_to_rgb(mcu));
-            count = 0;
-            mcu = Vec::new();
+            (self.current, y)
+        };
+        let k = (x / mcu_w) as usize - self.first_col;
+        let mcu = mcus.get(k).unwrap_or(&self.current[k]);
+        let (x, y) = ((x % mcu_w) as usize, y as usize);
+        mcu[first_block + (y / n) * h + x / n][y % n][x % n]
+    }
+
+    /// Component `c` at pixel column `x` of the image and row `i` of the
+    /// current MCU row.
+    fn upsampled(&self, c: usize, first_block: usize, x: usize, i: usize, upsampling: Upsampling) -> f32 {
+        let layout = self.layout;
+        let (h, v) = layout.factors[c];
+        let (x_near, x_far, wx) = taps(x, h, layout.h_max, upsampling);
+        let (y_near, y_far, wy) = taps(i, v, layout.v_max, upsampling);
+
+        let column = |x| {
+            let near = self.sample(c, first_block, x, y_near);
+            if wy == 1. {
+                return near;
+            }
+            wy * near + (1. - wy) * self.sample(c, first_block, x, y_far)
+        };
+        let near = column(x_near);
+        if wx == 1. {
+            return near;
         }
+        wx * near + (1. - wx) * column(x_far)
     }
 
-    res
+    /// Converts the MCUs of the current row to pixel rows, as wide as the
+    /// MCUs, edge ones included.
+    pub fn to_rgb(&self, upsampling: Upsampling, conversion: ColorConversion) -> Vec<Vec<[u8; 3]>> {
+        let (mcu_w, mcu_h) = self.layout.mcu_size(self.scale);
+        let first = self.layout.first_blocks();
+        let x0 = self.first_col * mcu_w;
+        let xs = x0..x0 + self.current.len() * mcu_w;
+        let kernel = simd::detected();
+
+        (0..mcu_h)
+            .map(|i| {
+                let plane = |c: usize| -> Vec<f32> {
+                    match first.get(c) {
+                        Some(&first) => xs.clone().map(|x| self.upsampled(c, first, x, i, upsampling)).collect(),
+                        // Grayscale
+                        None => vec![128.; xs.len()],
+                    }
+                };
+                let (y, cb, cr) = (plane(0), plane(1), plane(2));
+                match conversion {
+                    ColorConversion::Float => {
+                        let mut pixels = vec![[0; 3]; xs.len()];
+                        simd::ycbcr_row(kernel, &y, &cb, &cr, &mut pixels);
+                        pixels
+                    }
+                    ColorConversion::FixedPoint => (0..xs.len())
+                        .map(|x| ycbcr_to_rgb_fixed(sample_u8(y[x]), sample_u8(cb[x]), sample_u8(cr[x])))
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// Samples of the first component, i.e. the luma, rounded to 8 bits.
+    /// The chroma is neither upsampled nor converted.
+    pub fn to_luma(&self, upsampling: Upsampling) -> Vec<Vec<u8>> {
+        let (mcu_w, mcu_h) = self.layout.mcu_size(self.scale);
+        let first = self.layout.first_blocks()[0];
+        let x0 = self.first_col * mcu_w;
+        let xs = x0..x0 + self.current.len() * mcu_w;
+
+        (0..mcu_h)
+            .map(|i| xs.clone().map(|x| sample_u8(self.upsampled(0, first, x, i, upsampling))).collect())
+            .collect()
+    }
 }
 
+/// Converts the blocks of every MCU of the image, in scan order. If
+/// `parallel`, the MCUs then the MCU rows are converted in parallel, see
+/// `parallel::map`. `None` if there are blocks missing.
+pub fn blocks_to_image(
+    segments: &parsing::Segments,
+    vec: &[[i16; 64]],
+    scale: Scale,
+    upsampling: Upsampling,
+    conversion: ColorConversion,
+    parallel: bool,
+) -> Option<Vec<Vec<[u8; 3]>>> {
+    let layout = scan::McuLayout::new(segments.start_of_frame.as_ref().unwrap());
+    if vec.len() < layout.mcus_wide * layout.mcus_high * layout.blocks_per_mcu() {
+        return None;
+    }
+    let tables = block_tables(segments);
 
-pub fn mcus_to_img(mcus: Vec<[[[u8; 3]; 16]; 16]>, height: u16, width: u16) -> Vec<Vec<[u8; 3]>> {
-    let mut img = Vec::new();  
-    let h_mcus = usize::div_ceil(width as usize, 16);
+    let mcus: Vec<&[[i16; 64]]> = vec.chunks_exact(layout.blocks_per_mcu()).collect();
+    let samples: Vec<McuSamples> = parallel::map(&mcus, parallel, |blocks| blocks_to_samples(blocks, &tables, scale));
+    Some(samples_to_image(&layout, &samples, scale, upsampling, conversion, parallel))
+}
 
-    for i in 0..height as usize {
-        let mut row = Vec::new();
-        for j in 0..width as usize {
-            let mcus_idx = (j >> 4) + (i >> 4) * h_mcus;
-            let mcu_i = i % 16;
-            let mcu_j = j % 16;
-            let pixel = mcus[mcus_idx][mcu_i][mcu_j];
-            row.push(pixel);
-        }
-        img.push(row);
+/// Converts the samples of every MCU of the image, in scan order, see
+/// `blocks_to_image`.
+pub fn samples_to_image(
+    layout: &scan::McuLayout,
+    samples: &[McuSamples],
+    scale: Scale,
+    upsampling: Upsampling,
+    conversion: ColorConversion,
+    parallel: bool,
+) -> Vec<Vec<[u8; 3]>> {
+    let rows: Vec<&[McuSamples]> = samples.chunks(layout.mcus_wide).collect();
+
+    let row_indices: Vec<usize> = (0..rows.len()).collect();
+    let mut img: Vec<Vec<[u8; 3]>> = parallel::map(&row_indices, parallel, |&r| {
+        let row = McuRow {
+            above: r.checked_sub(1).map(|r| rows[r]),
+            current: rows[r],
+            below: rows.get(r + 1).copied(),
+            first_col: 0,
+            row: r,
+            layout,
+            scale,
+        };
+        row.to_rgb(upsampling, conversion)
+    })
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let width = scale.scaled(layout.width) as usize;
+    img.truncate(scale.scaled(layout.height) as usize);
+    for row in img.iter_mut() {
+        row.truncate(width);
     }
     img
 }
 
+/// Pixel rows of the MCUs in the rectangle `cols` x `rows` (in MCU units).
+/// Entropy decoding stops after the last MCU of the rectangle and starts at
+/// the closest restart interval before the first one. Only the MCUs of the
+/// rectangle and the ones around it, whose chroma is needed by the
+/// upsampling, are transformed and kept, the others are discarded once
+/// decoded. `Error::Truncated` if the scan data ends
+/// before the last of them.
+pub fn decode_region_mcus(segments: &parsing::Segments, cols: Range<usize>, rows: Range<usize>, upsampling: Upsampling, conversion: ColorConversion) -> Result<Vec<Vec<[u8; 3]>>, Error> {
+    let layout = scan::McuLayout::new(segments.start_of_frame.as_ref().unwrap());
+    let h_mcus = layout.mcus_wide;
+    let ext_cols = cols.start.saturating_sub(1)..usize::min(cols.end + 1, h_mcus);
+    let ext_rows = rows.start.saturating_sub(1)..usize::min(rows.end + 1, layout.mcus_high);
+    let first = ext_rows.start * h_mcus + ext_cols.start;
+    let last = (ext_rows.end - 1) * h_mcus + ext_cols.end;
+
+    let tables = block_tables(segments);
+    let mut samples: Vec<Vec<McuSamples>> = vec![Vec::new(); ext_rows.len()];
+    let end = scan::scan_mcu_range(segments, first, last, |mcu, blocks| {
+        let (r, c) = (mcu / h_mcus, mcu % h_mcus);
+        if ext_rows.contains(&r) && ext_cols.contains(&c) {
+            samples[r - ext_rows.start].push(blocks_to_samples(blocks, &tables, Scale::Full));
+        }
+    })?;
+    if end < last {
+        return Err(Error::Truncated);
+    }
+
+    let (mcu_w, _) = layout.mcu_size(Scale::Full);
+    let skip = (cols.start - ext_cols.start) * mcu_w;
+    let mut img = Vec::new();
+    for r in rows.clone() {
+        let k = r - ext_rows.start;
+        let row = McuRow {
+            above: k.checked_sub(1).map(|k| &samples[k][..]),
+            current: &samples[k],
+            below: samples.get(k + 1).map(Vec::as_slice),
+            first_col: ext_cols.start,
+            row: r,
+            layout: &layout,
+            scale: Scale::Full,
+        };
+        img.extend(row.to_rgb(upsampling, conversion).into_iter().map(|line| line[skip..skip + cols.len() * mcu_w].to_vec()));
+    }
+    Ok(img)
+}
+
+/// Crops the `width` x `height` pixels at (`x`, `y`) out of `img`.
+pub fn crop<T: Clone>(img: Vec<Vec<T>>, x: usize, y: usize, width: usize, height: usize) -> Vec<Vec<T>> {
+    img.into_iter()
+        .skip(y)
+        .take(height)
+        .map(|row| row[x..x + width].to_vec())
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
-    // use super::*;
+    use std::fs;
+    use crate::{decode_binary, decode_binary_with, decode_region_with, DecodeOptions, IncrementalDecoder, RowDecoder, Status};
+    use super::*;
 
     #[test]
     fn test_n_mcus() {
-        
+        let pixels = vec![vec![[0; 3]; 35]; 17];
+        let layouts = [
+            (vec![(1, 1)], (5, 3), 1),
+            // A single component is not interleaved
+            (vec![(2, 2)], (5, 3), 1),
+            (vec![(1, 1), (1, 1), (1, 1)], (5, 3), 3),
+            (vec![(2, 1), (1, 1), (1, 1)], (3, 3), 4),
+            (vec![(2, 2), (1, 1), (1, 1)], (3, 2), 6),
+            (vec![(4, 1), (1, 1), (1, 1)], (2, 3), 6),
+        ];
+        for (factors, mcus, blocks) in layouts {
+            let data = crate::encoder::encode(&pixels, &factors, 90, 0);
+            let segments = parsing::parse(&data);
+            let layout = scan::McuLayout::new(segments.start_of_frame.as_ref().unwrap());
+            assert_eq!((layout.mcus_wide, layout.mcus_high), mcus, "{factors:?}");
+            assert_eq!(layout.blocks_per_mcu(), blocks, "{factors:?}");
+            assert_eq!(scan::scan_blocks(&parsing::parse(&data)).unwrap().len(), mcus.0 * mcus.1 * blocks, "{factors:?}");
+        }
+    }
+
+    #[test]
+    fn test_idct_scaled() {
+        let mut input = [[0; 8]; 8];
+        input[0][0] = 80;
+        input[0][1] = -30;
+        input[1][0] = 12;
+
+        let full = idct(input);
+        for n in [1, 2, 4] {
+            let scaled = idct_scaled(input, n);
+            let step = 8 / n;
+            for (y, row) in scaled.iter().take(n).enumerate() {
+                for (x, &value) in row.iter().take(n).enumerate() {
+                    let mut mean = 0.;
+                    for row in full.iter().skip(y * step).take(step) {
+                        mean += row.iter().skip(x * step).take(step).sum::<f32>();
+                    }
+                    mean /= (step * step) as f32;
+                    assert!((value - mean).abs() < 2., "n: {n}, y: {y}, x: {x}");
+                }
+            }
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_fancy_upsampling() {
+        // Two 2x2 pixel 4:2:0 MCUs (1/8 scale) with one Cb sample each
+        let layout = scan::McuLayout {
+            width: 32,
+            height: 16,
+            factors: vec![(2, 2), (1, 1), (1, 1)],
+            h_max: 2,
+            v_max: 2,
+            mcus_wide: 2,
+            mcus_high: 1,
+        };
+        let mcu = |cb: f32| {
+            let mut blocks = vec![[[128.; 8]; 8]; 6];
+            blocks[4][0][0] = cb;
+            blocks
+        };
+        let current = [mcu(100.), mcu(200.)];
+        let row = McuRow {
+            above: None,
+            current: &current,
+            below: None,
+            first_col: 0,
+            row: 0,
+            layout: &layout,
+            scale: Scale::Eighth,
+        };
+
+        let cb: Vec<f32> = (0..4).map(|x| row.upsampled(1, 4, x, 0, Upsampling::Fancy)).collect();
+        assert_eq!(cb, [100., 125., 175., 200.]);
+        assert_eq!(row.upsampled(1, 4, 1, 1, Upsampling::Fancy), 125.);
+        assert_eq!(row.upsampled(1, 4, 1, 1, Upsampling::Nearest), 100.);
+        assert_eq!(row.upsampled(2, 5, 2, 0, Upsampling::Fancy), 128.);
+
+        let fancy = row.to_rgb(Upsampling::Fancy, ColorConversion::Float);
+        let nearest = row.to_rgb(Upsampling::Nearest, ColorConversion::Float);
+        assert_eq!((fancy.len(), fancy[0].len()), (2, 4));
+        assert_eq!(fancy[0][0], nearest[0][0]);
+        assert!(fancy[0][1][2] > nearest[0][1][2]);
+        assert!(fancy[0][2][2] < nearest[0][2][2]);
+    }
+
+    #[test]
+    fn test_scaled_dimensions() {
+        assert_eq!(Scale::Full.scaled(575), 575);
+        assert_eq!(Scale::Half.scaled(575), 288);
+        assert_eq!(Scale::Quarter.scaled(418), 105);
+        assert_eq!(Scale::Eighth.scaled(418), 53);
+    }
+
+    #[test]
+    fn test_fixed_point_conversion() {
+        // Values from libjpeg's jdcolor.c arithmetic
+        let expected = [
+            ((100, 50, 200), [201, 75, 0]),
+            ((200, 90, 60), [105, 255, 133]),
+            ((16, 240, 16), [0, 57, 214]),
+            ((77, 128, 255), [255, 0, 77]),
+            ((128, 128, 128), [128, 128, 128]),
+            ((255, 0, 0), [76, 255, 28]),
+        ];
+        for ((y, cb, cr), rgb) in expected {
+            assert_eq!(ycbcr_to_rgb_fixed(y, cb, cr), rgb, "{y} {cb} {cr}");
+        }
+
+        // The float conversion truncates where the fixed-point one rounds
+        for y in (0..=255).step_by(15) {
+            for cb in (0..=255).step_by(5) {
+                for cr in (0..=255).step_by(5) {
+                    let fixed = ycbcr_to_rgb_fixed(y, cb, cr);
+                    let float = ycbcr_to_rgb(y as f32, cb as f32, cr as f32);
+                    for c in 0..3 {
+                        assert!(fixed[c].abs_diff(float[c]) <= 1, "{y} {cb} {cr}: {fixed:?} {float:?}");
+                    }
+                }
+            }
+        }
+
+        assert_eq!(sample_u8(-3.), 0);
+        assert_eq!(sample_u8(12.5), 13);
+        assert_eq!(sample_u8(254.6), 255);
+    }
+
+    #[test]
+    fn test_upsampling() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let fancy = decode_binary(&data).unwrap();
+        let nearest = decode_binary_with(&data, &DecodeOptions { upsampling: Upsampling::Nearest, ..Default::default() }).unwrap();
+
+        assert_eq!(fancy.len(), nearest.len());
+        let diffs: Vec<i32> = fancy
+            .iter()
+            .flatten()
+            .zip(nearest.iter().flatten())
+            .flat_map(|(a, b)| (0..3).map(move |c| (a[c] as i32 - b[c] as i32).abs()))
+            .collect();
+        assert!(diffs.iter().any(|&d| d > 0));
+        let mean = diffs.iter().sum::<i32>() as f32 / diffs.len() as f32;
+        assert!(mean < 8., "mean difference: {mean}");
+    }
+
+    #[test]
+    fn test_color_conversion() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let float = decode_binary(&data).unwrap();
+        let options = DecodeOptions { conversion: ColorConversion::FixedPoint, ..Default::default() };
+        let fixed = decode_binary_with(&data, &options).unwrap();
+
+        // Mostly rounding differences. Out of range samples are clamped
+        // before the fixed-point conversion only, which moves the edges of
+        // saturated areas further
+        assert_ne!(fixed, float);
+        let diffs: Vec<u32> = fixed
+            .iter()
+            .flatten()
+            .zip(float.iter().flatten())
+            .flat_map(|(a, b)| (0..3).map(move |c| a[c].abs_diff(b[c]) as u32))
+            .collect();
+        let mean = diffs.iter().sum::<u32>() as f32 / diffs.len() as f32;
+        assert!(mean < 1., "mean difference: {mean}");
+
+        // Same conversion whatever the entry point
+        let rows = RowDecoder::with_options(&data, &options).unwrap().collect::<Result<Vec<_>, _>>().unwrap().concat();
+        assert_eq!(rows, fixed);
+        let region = decode_region_with(&data, 9, 3, 20, 10, &options).unwrap();
+        assert_eq!(region, crop(fixed.clone(), 9, 3, 20, 10));
+        let mut decoder = IncrementalDecoder::with_options(&options);
+        assert_eq!(decoder.feed(&data), Status::Done);
+        assert_eq!(decoder.into_image(), fixed);
+    }
+}
@@ -0,0 +1,340 @@
+//! Lossless transforms in the DCT domain, as done by `jpegtran`: the blocks
+//! are moved around and their quantized coefficients permuted and negated,
+//! so the image is rotated or flipped without being decoded and encoded
+//! again. Only the Huffman coding changes, to the tables of Annex K.
+
+use crate::coefficients::{read_coefficients_with, CoefficientImage, CoefficientOrder, ComponentCoefficients};
+use crate::dump::segments;
+use crate::encoder::write_coefficients;
+use crate::error::Error;
+use crate::limits::DecoderLimits;
+use crate::parsing;
+use crate::transf::ZIGZAG;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    /// Mirror around the vertical axis.
+    FlipHorizontal,
+    /// Mirror around the horizontal axis.
+    FlipVertical,
+    /// Mirror around the top-left to bottom-right diagonal.
+    Transpose,
+    /// Mirror around the top-right to bottom-left diagonal.
+    Transverse,
+    /// Clockwise rotation.
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Transform {
+    /// Whether the transform swaps rows and columns, then whether it
+    /// mirrors the result horizontally and vertically.
+    fn steps(self) -> (bool, bool, bool) {
+        match self {
+            Transform::FlipHorizontal => (false, true, false),
+            Transform::FlipVertical => (false, false, true),
+            Transform::Transpose => (true, false, false),
+            Transform::Transverse => (true, true, true),
+            Transform::Rotate90 => (true, true, false),
+            Transform::Rotate180 => (false, true, true),
+            Transform::Rotate270 => (true, false, true),
+        }
+    }
+}
+
+/// What to do with the partial MCUs of the right and bottom edges when
+/// they would have to be mirrored: their padding would end up inside the
+/// image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EdgeHandling {
+    /// Leave them on the edge, only transposed if need be, as `jpegtran`
+    /// does by default. The rest of the image is transformed.
+    #[default]
+    Keep,
+    /// Drop them, like `jpegtran -trim`. An image smaller than one MCU is
+    /// kept whole.
+    Trim,
+    /// Fail if there are any, like `jpegtran -perfect`.
+    Perfect,
+}
+
+/// Applies `transform` to a baseline JPEG without loss. The APPn and COM
+/// segments are copied as they are: an Exif orientation is not updated.
+/// The restart interval is kept, in MCUs of the transformed image.
+pub fn transform(data: &[u8], transform: Transform, edges: EdgeHandling) -> Result<Vec<u8>, Error> {
+    transform_with(data, transform, edges, &DecoderLimits::default())
+}
+
+/// Same as `transform`, within `limits`.
+pub fn transform_with(data: &[u8], transform: Transform, edges: EdgeHandling, limits: &DecoderLimits) -> Result<Vec<u8>, Error> {
+    let img = read_coefficients_with(data, CoefficientOrder::Natural, limits)?;
+    let transformed = transform_coefficients(&img, transform, edges)?;
+
+    // The segments before the scan were read above: only an error past them
+    // is possible here, and ignored
+    let markers: Vec<(u8, &[u8])> = segments(data).0
+        .iter()
+        .take_while(|s| s.marker != 0xDA)
+        .filter(|s| matches!(s.marker, 0xE0..=0xEF | 0xFE))
+        .map(|s| (s.marker, &data[s.offset + 4..s.offset + 2 + s.length]))
+        .collect();
+    write_coefficients(&transformed, parsing::parse(data).restart_interval, &markers)
+}
+
+/// `values` in natural order, transposed if `transpose`, and converted to
+/// zigzag order.
+fn to_zigzag<T: Copy + Default>(values: &[T; 64], transpose: bool) -> [T; 64] {
+    let mut res = [T::default(); 64];
+    for (v, row) in ZIGZAG.iter().enumerate() {
+        for (u, &i) in row.iter().enumerate() {
+            res[i] = if transpose { values[u * 8 + v] } else { values[v * 8 + u] };
+        }
+    }
+    res
+}
+
+/// Transforms the coefficients of `img`, in natural order, into an image in
+/// zigzag order.
+fn transform_coefficients(img: &CoefficientImage, transform: Transform, edges: EdgeHandling) -> Result<CoefficientImage, Error> {
+    if img.order != CoefficientOrder::Natural {
+        return Err(Error::InvalidArgument("Coefficients should be in natural order"));
+    }
+    let (transpose, mirror_h, mirror_v) = transform.steps();
+
+    let factors: Vec<(usize, usize)> = img
+        .components
+        .iter()
+        .map(|c| {
+            let (h, v) = (c.factors.0 as usize, c.factors.1 as usize);
+            if transpose { (v, h) } else { (h, v) }
+        })
+        .collect();
+    // A single component is not interleaved: its MCU is one block
+    let (mcu_w, mcu_h) = if factors.len() == 1 {
+        (8, 8)
+    } else {
+        (8 * factors.iter().map(|f| f.0).max().unwrap(), 8 * factors.iter().map(|f| f.1).max().unwrap())
+    };
+
+    let (mut width, mut height) = if transpose { (img.height, img.width) } else { (img.width, img.height) };
+    let (full_cols, full_rows) = (width as usize / mcu_w, height as usize / mcu_h);
+    let partial_col = mirror_h && !(width as usize).is_multiple_of(mcu_w);
+    let partial_row = mirror_v && !(height as usize).is_multiple_of(mcu_h);
+    match edges {
+        EdgeHandling::Keep => {}
+        EdgeHandling::Trim => {
+            if partial_col && full_cols > 0 {
+                width = (full_cols * mcu_w) as u16;
+            }
+            if partial_row && full_rows > 0 {
+                height = (full_rows * mcu_h) as u16;
+            }
+        }
+        EdgeHandling::Perfect => {
+            if partial_col || partial_row {
+                return Err(Error::Format("Partial MCUs cannot be transformed"));
+            }
+        }
+    }
+    let (mcus_wide, mcus_high) = ((width as usize).div_ceil(mcu_w), (height as usize).div_ceil(mcu_h));
+
+    let components = img
+        .components
+        .iter()
+        .zip(&factors)
+        .map(|(component, &(h, v))| {
+            let (h, v) = if factors.len() == 1 { (1, 1) } else { (h, v) };
+            let (blocks_wide, blocks_high) = (mcus_wide * h, mcus_high * v);
+            // Only the blocks of whole MCUs are mirrored
+            let (mirrored_wide, mirrored_high) = (full_cols * h, full_rows * v);
+
+            let mut blocks = Vec::with_capacity(blocks_wide * blocks_high);
+            for y in 0..blocks_high {
+                for x in 0..blocks_wide {
+                    let flip_h = mirror_h && x < mirrored_wide;
+                    let flip_v = mirror_v && y < mirrored_high;
+                    let sx = if flip_h { mirrored_wide - 1 - x } else { x };
+                    let sy = if flip_v { mirrored_high - 1 - y } else { y };
+                    let (sx, sy) = if transpose { (sy, sx) } else { (sx, sy) };
+
+                    // Mirroring negates the odd frequencies along its axis
+                    let mut block = to_zigzag(component.block(sx, sy), transpose);
+                    for (v, row) in ZIGZAG.iter().enumerate() {
+                        for (u, &i) in row.iter().enumerate() {
+                            if (flip_h && u % 2 == 1) != (flip_v && v % 2 == 1) {
+                                block[i] = -block[i];
+                            }
+                        }
+                    }
+                    blocks.push(block);
+                }
+            }
+
+            let (fh, fv) = component.factors;
+            ComponentCoefficients {
+                id: component.id,
+                factors: if transpose { (fv, fh) } else { (fh, fv) },
+                quantization_table: to_zigzag(&component.quantization_table, transpose),
+                blocks_wide,
+                blocks_high,
+                blocks,
+            }
+        })
+        .collect();
+
+    Ok(CoefficientImage { width, height, order: CoefficientOrder::Zigzag, components })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use crate::encoder::encode;
+    use crate::fixtures::pattern;
+    use crate::{decode_binary_with, read_coefficients, DecodeOptions, Upsampling};
+    use super::*;
+
+    const ALL: [Transform; 7] = [
+        Transform::FlipHorizontal,
+        Transform::FlipVertical,
+        Transform::Transpose,
+        Transform::Transverse,
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+    ];
+
+    /// Pixel of the original image at (`x`, `y`) of the transformed one.
+    fn source(transform: Transform, (width, height): (usize, usize), x: usize, y: usize) -> (usize, usize) {
+        match transform {
+            Transform::FlipHorizontal => (width - 1 - x, y),
+            Transform::FlipVertical => (x, height - 1 - y),
+            Transform::Transpose => (y, x),
+            Transform::Transverse => (width - 1 - y, height - 1 - x),
+            Transform::Rotate90 => (y, height - 1 - x),
+            Transform::Rotate180 => (width - 1 - x, height - 1 - y),
+            Transform::Rotate270 => (width - 1 - y, x),
+        }
+    }
+
+    fn decode(data: &[u8]) -> Vec<Vec<[u8; 3]>> {
+        decode_binary_with(data, &DecodeOptions { upsampling: Upsampling::Nearest, ..Default::default() }).unwrap()
+    }
+
+    /// Checks that the pixels of `transformed` are the ones of `original`
+    /// moved by `transform`, up to rounding in the IDCT.
+    fn check(original: &[Vec<[u8; 3]>], transformed: &[Vec<[u8; 3]>], transform: Transform, context: &str) {
+        let size = (original[0].len(), original.len());
+        for (y, row) in transformed.iter().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                let (sx, sy) = source(transform, size, x, y);
+                for c in 0..3 {
+                    assert!(pixel[c].abs_diff(original[sy][sx][c]) <= 1, "{context}: {x}, {y}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_transform() {
+        let modes = [vec![(1, 1)], vec![(2, 2), (1, 1), (1, 1)], vec![(2, 1), (1, 1), (1, 1)], vec![(1, 2), (1, 1), (1, 1)]];
+        for factors in &modes {
+            let data = encode(&pattern(48, 32), factors, 85, 0);
+            let original = decode(&data);
+            let coefficients = read_coefficients(&data, CoefficientOrder::Zigzag).unwrap();
+            for t in ALL {
+                let context = format!("{factors:?} {t:?}");
+                let res = transform(&data, t, EdgeHandling::Perfect).unwrap();
+                let transformed = decode(&res);
+                let expected = if t.steps().0 { (32, 48) } else { (48, 32) };
+                assert_eq!((transformed[0].len(), transformed.len()), expected, "{context}");
+                check(&original, &transformed, t, &context);
+
+                // Each transform but the rotations by a quarter turn is its
+                // own inverse
+                let inverse = match t {
+                    Transform::Rotate90 => Transform::Rotate270,
+                    Transform::Rotate270 => Transform::Rotate90,
+                    other => other,
+                };
+                let back = transform(&res, inverse, EdgeHandling::Perfect).unwrap();
+                let round_trip = read_coefficients(&back, CoefficientOrder::Zigzag).unwrap();
+                for (a, b) in round_trip.components.iter().zip(&coefficients.components) {
+                    assert_eq!((a.factors, &a.blocks), (b.factors, &b.blocks), "{context}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_partial_mcus() {
+        let data = encode(&pattern(35, 21), &[(2, 2), (1, 1), (1, 1)], 85, 0);
+        let original = decode(&data);
+
+        for t in ALL {
+            let context = format!("{t:?}");
+            let (transpose, mirror_h, mirror_v) = t.steps();
+            let (width, height) = if transpose { (21, 35) } else { (35, 21) };
+
+            let kept = decode(&transform(&data, t, EdgeHandling::Keep).unwrap());
+            assert_eq!((kept[0].len(), kept.len()), (width, height), "{context}");
+
+            // Only whole MCUs of 16x16 pixels remain along the mirrored
+            // directions, transformed as if the image was that size
+            let trimmed = decode(&transform(&data, t, EdgeHandling::Trim).unwrap());
+            let trim = |size: usize, mirror: bool| if mirror { size / 16 * 16 } else { size };
+            let (trimmed_width, trimmed_height) = (trim(width, mirror_h), trim(height, mirror_v));
+            assert_eq!((trimmed[0].len(), trimmed.len()), (trimmed_width, trimmed_height), "{context}");
+            let (crop_width, crop_height) =
+                if transpose { (trimmed_height, trimmed_width) } else { (trimmed_width, trimmed_height) };
+            let cropped: Vec<Vec<[u8; 3]>> = original[..crop_height].iter().map(|row| row[..crop_width].to_vec()).collect();
+            check(&cropped, &trimmed, t, &context);
+
+            // Kept edges aside, the same image
+            for (row, expected) in kept.iter().zip(&trimmed) {
+                assert_eq!(row[..trimmed_width], expected[..], "{context}");
+            }
+
+            let perfect = transform(&data, t, EdgeHandling::Perfect);
+            if (width, height) != (trimmed_width, trimmed_height) {
+                assert_eq!(perfect, Err(Error::Format("Partial MCUs cannot be transformed")), "{context}");
+            } else {
+                assert!(perfect.is_ok(), "{context}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_transform_fixture() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        let res = transform(&data, Transform::Rotate180, EdgeHandling::Keep).unwrap();
+        let back = transform(&res, Transform::Rotate180, EdgeHandling::Keep).unwrap();
+        let (a, b) = (read_coefficients(&data, CoefficientOrder::Zigzag).unwrap(), read_coefficients(&back, CoefficientOrder::Zigzag).unwrap());
+        for (a, b) in a.components.iter().zip(&b.components) {
+            assert_eq!(a.blocks, b.blocks);
+        }
+
+        // The APP0 and COM segments are kept
+        let markers = |data: &[u8]| segments(data).0.iter().filter(|s| matches!(s.marker, 0xE0..=0xEF | 0xFE)).count();
+        assert_eq!(markers(&res), markers(&data));
+        assert!(transform(&fs::read("img/carnaval.jpg").unwrap(), Transform::Rotate90, EdgeHandling::Keep).is_err());
+    }
+
+    #[test]
+    fn test_transform_restart_interval() {
+        let data = encode(&pattern(48, 32), &[(2, 2), (1, 1), (1, 1)], 85, 2);
+        let original = decode(&data);
+        let res = transform(&data, Transform::Rotate90, EdgeHandling::Perfect).unwrap();
+        assert_eq!(parsing::parse(&res).restart_interval, 2);
+        check(&original, &decode(&res), Transform::Rotate90, "restarts");
+    }
+
+    #[test]
+    fn test_transform_invalid() {
+        let data = fs::read("img/rec32dot.jpg").expect("Failed to read image");
+        assert_eq!(transform(&data[..800], Transform::Rotate90, EdgeHandling::Keep), Err(Error::Truncated));
+
+        let zigzag = read_coefficients(&data, CoefficientOrder::Zigzag).unwrap();
+        assert!(matches!(transform_coefficients(&zigzag, Transform::Rotate90, EdgeHandling::Keep), Err(Error::InvalidArgument(_))));
+    }
+}